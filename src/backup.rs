@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use sled::Tree;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::catalog;
+use crate::changes::{self, Change, ChangeKind};
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::fts;
+use crate::getset::{EasyGet, GetSet};
+use crate::matview::{self, MatView};
+use crate::query::Query;
+use crate::trigger;
+
+// An archive is JSON Lines: a header, then one line per section. Checksums
+// are taken over the section lines exactly as written.
+const FORMAT: u32 = 2;
+/// Largest archive accepted in a restore request body.
+pub const RESTORE_LIMIT: u64 = 256 << 20;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TableDump {
+    pub name: String,
+    pub schema: Schema,
+    pub records: Vec<Record>,
+    /// Non-finite reals as (ident, column, bits); JSON has no NaN or infinity,
+    /// so `records` holds Null in their place.
+    #[serde(default)]
+    pub reals: Vec<(u64, usize, u64)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Archive {
+    pub format: u32,
+    pub database: String,
+    pub seq: u64,
+    pub tables: Vec<TableDump>,
+    pub views: Vec<(String, Query)>,
+    pub matviews: Vec<(String, MatView)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    format: u32,
+    database: String,
+    seq: u64,
+    checksums: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+enum SectionRef<'a> {
+    Table(&'a TableDump),
+    Views(&'a [(String, Query)]),
+    MatViews(&'a [(String, MatView)]),
+}
+
+#[derive(Debug, Deserialize)]
+enum Section {
+    Table(TableDump),
+    Views(Vec<(String, Query)>),
+    MatViews(Vec<(String, MatView)>),
+}
+
+fn checksum(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+impl TableDump {
+    fn new(name: &str, schema: Schema, mut records: Vec<Record>) -> TableDump {
+        let mut reals = vec![];
+        for r in &mut records {
+            for (i, v) in r.value.iter_mut().enumerate() {
+                if let DBValue::Real(f) = *v {
+                    if !f.is_finite() {
+                        reals.push((r.ident, i, f.to_bits()));
+                        *v = DBValue::Null;
+                    }
+                }
+            }
+        }
+        TableDump { name: name.to_string(), schema, records, reals }
+    }
+
+    fn values(&self) -> DBResult<Vec<Record>> {
+        let mut records = self.records.clone();
+        let pos: BTreeMap<u64, usize> = records.iter().enumerate().map(|(i, r)| (r.ident, i)).collect();
+        for (ident, column, bits) in &self.reals {
+            let cell = pos.get(ident)
+                .and_then(|&i| records[i].value.get_mut(*column))
+                .ok_or(InvalidQuery)?;
+            *cell = DBValue::Real(f64::from_bits(*bits));
+        }
+        Ok(records)
+    }
+}
+
+impl Archive {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut sections: Vec<(String, SectionRef)> = self.tables.iter()
+            .map(|t| (format!("table:{}", t.name), SectionRef::Table(t)))
+            .collect();
+        sections.push(("views".to_string(), SectionRef::Views(&self.views)));
+        sections.push(("matviews".to_string(), SectionRef::MatViews(&self.matviews)));
+        let lines: Vec<(String, Vec<u8>)> = sections.into_iter()
+            .map(|(k, s)| (k, serde_json::to_vec(&s).unwrap()))
+            .collect();
+        let header = Header {
+            format: self.format,
+            database: self.database.clone(),
+            seq: self.seq,
+            checksums: lines.iter().map(|(k, l)| (k.clone(), checksum(l))).collect(),
+        };
+        let mut out = serde_json::to_vec(&header).unwrap();
+        for (_, line) in lines {
+            out.push(b'\n');
+            out.extend(line);
+        }
+        out.push(b'\n');
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> DBResult<Archive> {
+        let mut lines = data.split(|b| *b == b'\n').filter(|l| !l.is_empty());
+        let header: Header = lines.next()
+            .and_then(|l| serde_json::from_slice(l).ok())
+            .ok_or(InvalidQuery)?;
+        if header.format != FORMAT {
+            return Err(InvalidQuery);
+        }
+        let mut archive = Archive { format: header.format, database: header.database, seq: header.seq, tables: vec![], views: vec![], matviews: vec![] };
+        let mut seen = BTreeMap::new();
+        for line in lines {
+            let section: Section = serde_json::from_slice(line).map_err(|_| InvalidQuery)?;
+            let key = match section {
+                Section::Table(t) => {
+                    let key = format!("table:{}", t.name);
+                    archive.tables.push(t);
+                    key
+                },
+                Section::Views(v) => {
+                    archive.views = v;
+                    "views".to_string()
+                },
+                Section::MatViews(m) => {
+                    archive.matviews = m;
+                    "matviews".to_string()
+                }
+            };
+            if seen.insert(key, checksum(line)).is_some() {
+                return Err(InvalidQuery);
+            }
+        }
+        if seen != header.checksums {
+            return Err(ChecksumMismatch);
+        }
+        Ok(archive)
+    }
+
+    pub fn load(name: &str) -> DBResult<Archive> {
+        let data = fs::read(path(name)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => BackupNotFound,
+            _ => StoreError
+        })?;
+        Archive::decode(&data)
+    }
+
+    pub fn save(&self, name: &str) -> DBResult<PathBuf> {
+        let file = path(name)?;
+        fs::create_dir_all(catalog::DATA_DIR.join(catalog::BACKUPS)).map_err(|_| StoreError)?;
+        let tmp = file.with_extension("tmp");
+        fs::write(&tmp, self.encode()).map_err(|_| StoreError)?;
+        fs::rename(&tmp, &file).map_err(|_| StoreError)?;
+        Ok(file)
+    }
+}
+
+pub fn restore_new(dbs: &mut BTreeMap<String, DB<Tree>>, name: &str, archive: &Archive) -> DBResult<()> {
+    if dbs.contains_key(name) || catalog::Manifest::load().contains(name) || catalog::path(name)?.exists() {
+        return Err(DatabaseExists);
+    }
+    let mut db = DB::new(name)?;
+    if let Err(e) = db.atomic(|db| db.restore(archive)) {
+        drop(db);
+        let _ = fs::remove_dir_all(catalog::path(name)?);
+        let _ = catalog::unregister(name);
+        return Err(e);
+    }
+    dbs.insert(name.to_string(), db);
+    Ok(())
+}
+
+pub fn path(name: &str) -> DBResult<PathBuf> {
+    catalog::validate_name(name)?;
+    Ok(catalog::DATA_DIR.join(catalog::BACKUPS).join(format!("{}.jsonl", name)))
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    pub fn backup(&mut self, database: &str) -> DBResult<Archive> {
+        let mut tables = vec![];
+        for name in self.get_tables()? {
+            let table = Table::load(&name, &mut self.tree)?;
+            tables.push(TableDump::new(&name, table.schema.clone(), table.get_records()));
+        }
+        let views = self.get_views().into_iter()
+            .map(|v| self.tree.get_value(&format!("@{}", v)).map(|q| (v, q)).ok_or(TableNotFound))
+            .collect::<DBResult<Vec<_>>>()?;
+        let matviews = matview::get_matviews(&self.tree).into_iter()
+            .map(|m| matview::status(&self.tree, &m).map(|(def, _)| (m, def)))
+            .collect::<DBResult<Vec<_>>>()?;
+        Ok(Archive {
+            format: FORMAT,
+            database: database.to_string(),
+            seq: self.tree.get_value("^").unwrap_or(0),
+            tables,
+            views,
+            matviews,
+        })
+    }
+
+    pub fn restore(&mut self, archive: &Archive) -> DBResult<()> {
+        if !self.get_tables()?.is_empty() || !self.get_views().is_empty() {
+            return Err(DatabaseExists);
+        }
+        let mut names = vec![];
+        for dump in &archive.tables {
+            let (name, schema) = (&dump.name, &dump.schema);
+            validate_table_name(name)?;
+            for column in &schema.columns {
+                column.validate()?;
+            }
+            schema.validate_checks()?;
+            let records = dump.values()?;
+            let idents: Vec<u64> = records.iter().map(|r| r.ident).collect();
+            if idents.iter().collect::<BTreeSet<_>>().len() != idents.len() {
+                return Err(InvalidQuery);
+            }
+            for Record { ident, value } in &records {
+                if !schema.match_record(value) {
+                    return Err(TypeMismatch);
+                }
+                schema.check_record(value)?;
+                self.tree.set_value(&record_key(name, *ident), value);
+                fts::index_row(&self.tree, name, schema, *ident, value, true);
+            }
+            if schema.ids != IdGen::Random {
+                self.tree.set_value(&last_id_key(name), &idents.iter().cloned().max().unwrap_or(0));
+            }
+            self.tree.set_value(&format!("/{}", name), &idents);
+            self.tree.set_value(&format!("#{}", name), schema);
+            changes::record(&self.tree, Change::table(name, ChangeKind::Schema, Some(schema)));
+            names.push(name.clone());
+        }
+        self.tree.set_value("/", &names);
+        // References and triggers may point at tables later in the archive.
+        for dump in &archive.tables {
+            for column in &dump.schema.columns {
+                check_reference(&self.tree, &dump.name, &dump.schema, column)?;
+            }
+            trigger::validate(&dump.schema)?;
+            let targets = dump.schema.triggers.iter().flat_map(|t| t.actions.iter().filter_map(|a| a.table()));
+            for t in targets {
+                if !names.iter().any(|n| n == t) {
+                    return Err(TableNotFound);
+                }
+            }
+            let mut table = Table::load(&dump.name, &mut self.tree)?;
+            for Record { value, .. } in table.get_records() {
+                table.check_references(&value)?;
+            }
+        }
+        let mut views: Vec<&str> = vec![];
+        for (name, query) in &archive.views {
+            validate_table_name(name)?;
+            if names.contains(name) || views.contains(&name.as_str()) {
+                return Err(TableExists);
+            }
+            views.push(name);
+            self.tree.set_value(&format!("@{}", name), query);
+        }
+        self.tree.set_value("@", &archive.views.iter().map(|(v, _)| v.clone()).collect::<Vec<_>>());
+        for (name, def) in &archive.matviews {
+            matview::define(&self.tree, name, def);
+        }
+        for (name, _) in &archive.matviews {
+            self.refresh_matview(name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Expr;
+    use crate::getset::MemStore;
+    use crate::trigger::{Action, Event, Timing, Trigger};
+
+    fn source() -> DB<MemStore> {
+        let mut db = DB::in_memory(MemStore::default());
+        let schema = Schema {
+            columns: vec![Column::new("name", Type::Str), Column { nullable: true, ..Column::new("score", Type::Real) }],
+            ids: IdGen::Sequence,
+            ..Schema::default()
+        };
+        db.add_table("t", &schema).unwrap();
+        for (name, score) in &[("a", DBValue::Real(1.5)), ("b", DBValue::Real(std::f64::NAN)), ("c", DBValue::Real(std::f64::NEG_INFINITY)), ("d", DBValue::Null)] {
+            db.get_table("t").unwrap().add_record(&[DBValue::Str(name.to_string()), score.clone()]).unwrap();
+        }
+        let query = Query { from: "t".to_string(), alias: None, joins: vec![], filter: Some(Expr::parse("score > 1").unwrap()), select: None };
+        db.add_view("high", &query).unwrap();
+        db
+    }
+
+    // Everything but the header, which carries the change sequence.
+    fn body(archive: &Archive) -> Vec<u8> {
+        let data = archive.encode();
+        let start = data.iter().position(|b| *b == b'\n').unwrap();
+        data[start..].to_vec()
+    }
+
+    #[test]
+    fn backup_restore_round_trips() {
+        let archive = source().backup("src").unwrap();
+        let decoded = Archive::decode(&archive.encode()).unwrap();
+        let mut copy = DB::in_memory(MemStore::default());
+        copy.restore(&decoded).unwrap();
+
+        let restored = copy.backup("src").unwrap();
+        assert_eq!(body(&restored), body(&archive));
+        let values: Vec<DBValue> = copy.get_table("t").unwrap().get_records().into_iter().map(|r| r.value[1].clone()).collect();
+        match values[1] {
+            DBValue::Real(f) => assert!(f.is_nan()),
+            ref v => panic!("unexpected {:?}", v)
+        }
+        assert_eq!(values[2], DBValue::Real(std::f64::NEG_INFINITY));
+        assert_eq!(copy.get_table("t").unwrap().add_record(&[DBValue::Str("e".to_string()), DBValue::Null]).unwrap(), 5);
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let data = source().backup("src").unwrap().encode();
+        let text = String::from_utf8(data).unwrap().replace("\"a\"", "\"z\"");
+        match Archive::decode(text.as_bytes()) {
+            Err(ChecksumMismatch) => (),
+            r => panic!("unexpected {:?}", r.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn rejects_rows_that_break_the_schema() {
+        let mut archive = source().backup("src").unwrap();
+        archive.tables[0].records[0].value[0] = DBValue::Integer(1);
+        let mut copy = DB::in_memory(MemStore::default());
+        match copy.dry_run(|db| db.restore(&archive)) {
+            Err(TypeMismatch) => (),
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    fn restore(archive: &Archive) -> DBResult<()> {
+        DB::in_memory(MemStore::default()).dry_run(|db| db.restore(archive))
+    }
+
+    #[test]
+    fn rejects_inconsistent_archives() {
+        let archive = source().backup("src").unwrap();
+
+        let mut duplicate = archive.clone();
+        let row = duplicate.tables[0].records[0].clone();
+        duplicate.tables[0].records.push(row);
+        assert!(match restore(&duplicate) { Err(InvalidQuery) => true, _ => false });
+
+        let mut clash = archive.clone();
+        clash.views[0].0 = "t".to_string();
+        assert!(match restore(&clash) { Err(TableExists) => true, _ => false });
+
+        let mut dangling = archive.clone();
+        dangling.tables[0].schema.columns[1].references = Some(Reference { table: "gone".to_string(), column: None, on_delete: RefAction::default(), on_update: RefAction::default() });
+        assert!(match restore(&dangling) { Err(InvalidReference) => true, _ => false });
+
+        let mut trigger = archive.clone();
+        trigger.tables[0].schema.triggers.push(Trigger {
+            name: "log".to_string(),
+            timing: Timing::After,
+            events: vec![Event::Insert],
+            when: None,
+            actions: vec![Action::Delete { table: "gone".to_string(), filter: None }],
+        });
+        assert!(match restore(&trigger) { Err(TableNotFound) => true, _ => false });
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use rand::Rng;
+use serde_derive::{Serialize, Deserialize};
+use sled::Tree;
+
+use crate::changes;
+use crate::db::*;
+use crate::db::DBError::*;
+
+const MANIFEST: &str = "manifest.json";
+const FORMAT: u32 = 2;
+const MAX_NAME: usize = 64;
+const RESERVED: &[&str] = &["con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9", BACKUPS];
+pub const BACKUPS: &str = "backups";
+
+lazy_static! {
+    /// Where databases, the manifest and backups live: `DB_DATA_DIR`, or the
+    /// working directory, which is where databases were always created.
+    pub static ref DATA_DIR: PathBuf = env::var("DB_DATA_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("."));
+    static ref PENDING_DROPS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub format: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Listing {
+    pub name: String,
+    pub open: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Manifest {
+    pub databases: Vec<Entry>,
+}
+
+pub fn validate_name(name: &str) -> DBResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && !name.starts_with('-')
+        && !RESERVED.contains(&name.to_ascii_lowercase().as_str());
+    if !valid {
+        return Err(InvalidDatabaseName);
+    }
+    Ok(())
+}
+
+pub fn path(name: &str) -> DBResult<PathBuf> {
+    validate_name(name)?;
+    Ok(DATA_DIR.join(name))
+}
+
+impl Manifest {
+    pub fn load() -> Manifest {
+        fs::read(DATA_DIR.join(MANIFEST)).ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        fs::create_dir_all(&*DATA_DIR)?;
+        let tmp = DATA_DIR.join(format!("{}.tmp", MANIFEST));
+        fs::write(&tmp, serde_json::to_vec_pretty(self).unwrap())?;
+        fs::rename(tmp, DATA_DIR.join(MANIFEST))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.databases.iter().any(|e| e.name == name)
+    }
+}
+
+pub fn register(name: &str) -> io::Result<()> {
+    let mut manifest = Manifest::load();
+    match manifest.databases.iter_mut().find(|e| e.name == name) {
+        Some(e) if e.format == FORMAT => return Ok(()),
+        Some(e) => e.format = FORMAT,
+        None => manifest.databases.push(Entry { name: name.to_string(), format: FORMAT })
+    }
+    manifest.save()
+}
+
+pub fn unregister(name: &str) -> io::Result<()> {
+    let mut manifest = Manifest::load();
+    manifest.databases.retain(|e| e.name != name);
+    manifest.save()
+}
+
+pub fn list(dbs: &BTreeMap<String, DB<Tree>>) -> Vec<Listing> {
+    let mut names: BTreeSet<String> = Manifest::load().databases.into_iter().map(|e| e.name).collect();
+    names.extend(dbs.keys().cloned());
+    names.into_iter()
+        .map(|name| Listing { open: dbs.contains_key(&name), name })
+        .collect()
+}
+
+// Change feeds, live queries and exports hold a subscriber while they run.
+fn check_idle(name: &str) -> DBResult<()> {
+    if changes::subscribers(name) > 0 {
+        return Err(DatabaseBusy);
+    }
+    Ok(())
+}
+
+pub fn close(dbs: &mut BTreeMap<String, DB<Tree>>, name: &str) -> DBResult<()> {
+    let db = dbs.remove(name).ok_or(DatabaseNotFound)?;
+    db.tree.flush().map_err(|_| StoreError)
+}
+
+fn exists(dbs: &BTreeMap<String, DB<Tree>>, name: &str) -> DBResult<()> {
+    if !dbs.contains_key(name) && !Manifest::load().contains(name) {
+        return Err(DatabaseNotFound);
+    }
+    Ok(())
+}
+
+pub fn drop_token(dbs: &BTreeMap<String, DB<Tree>>, name: &str) -> DBResult<String> {
+    exists(dbs, name)?;
+    let token = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    PENDING_DROPS.lock().unwrap().insert(name.to_string(), token.clone());
+    Ok(token)
+}
+
+pub fn drop(dbs: &mut BTreeMap<String, DB<Tree>>, name: &str, token: &str) -> DBResult<()> {
+    exists(dbs, name)?;
+    {
+        let mut pending = PENDING_DROPS.lock().unwrap();
+        if pending.get(name).map(String::as_str) != Some(token) {
+            return Err(ConfirmationRequired);
+        }
+        check_idle(name)?;
+        pending.remove(name);
+    }
+    if dbs.contains_key(name) {
+        close(dbs, name)?;
+    }
+    let dir = path(name)?;
+    if dir.exists() {
+        fs::remove_dir_all(dir).map_err(|_| StoreError)?;
+    }
+    unregister(name).map_err(|_| StoreError)
+}
+
+pub fn rename(dbs: &mut BTreeMap<String, DB<Tree>>, name: &str, new: &str) -> DBResult<()> {
+    exists(dbs, name)?;
+    if dbs.contains_key(new) || Manifest::load().contains(new) || path(new)?.exists() {
+        return Err(DatabaseExists);
+    }
+    check_idle(name)?;
+    let was_open = dbs.contains_key(name);
+    if was_open {
+        close(dbs, name)?;
+    }
+    let (from, to) = (path(name)?, path(new)?);
+    fs::rename(&from, &to).map_err(|_| StoreError)?;
+    match DB::new(new) {
+        Ok(db) => {
+            dbs.insert(new.to_string(), db);
+            unregister(name).map_err(|_| StoreError)
+        },
+        Err(e) => {
+            let _ = unregister(new);
+            if fs::rename(&to, &from).is_ok() && was_open {
+                dbs.insert(name.to_string(), DB::new(name)?);
+            }
+            Err(e)
+        }
+    }
+}
+
+// Sled directories in DATA_DIR that the manifest doesn't know about, such as
+// databases created before it existed.
+fn unlisted(manifest: &Manifest) -> Vec<String> {
+    let entries = match fs::read_dir(&*DATA_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return vec![]
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().join("db").is_file())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|n| validate_name(n).is_ok() && !manifest.contains(n))
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn open_all() {
+    let mut dbs = DATABASES.lock().unwrap();
+    let manifest = Manifest::load();
+    let found = unlisted(&manifest);
+    for name in &found {
+        eprintln!("registering database {} found in {}", name, DATA_DIR.display());
+    }
+    let names = manifest.databases.into_iter().map(|e| e.name).chain(found);
+    for name in names {
+        match path(&name) {
+            Ok(p) if p.exists() => (),
+            Ok(_) => {
+                eprintln!("database {} is listed in the manifest but missing on disk", name);
+                continue;
+            },
+            Err(_) => {
+                eprintln!("database {} has an invalid name, skipping", name);
+                continue;
+            }
+        }
+        match DB::new(&name) {
+            Ok(db) => {
+                dbs.insert(name, db);
+            },
+            Err(e) => eprintln!("failed to open database {}: {:?}", name, e)
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, Read};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::getset::{EasyGet, GetSet};
+
+const KEEPALIVE: u64 = 15;
+/// Size of the chunks the event stream is written in. Each batch of events is
+/// padded to a multiple of it so that Rocket hands it to the client at once.
+pub const CHUNK: usize = 8192;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+    Schema,
+    Drop,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Change {
+    pub seq: u64,
+    pub table: String,
+    pub kind: ChangeKind,
+    pub ident: Option<u64>,
+    pub old: Option<Vec<DBValue>>,
+    pub new: Option<Vec<DBValue>>,
+    pub schema: Option<Schema>,
+}
+
+lazy_static! {
+    static ref SIGNAL: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
+    static ref SUBSCRIBERS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+    /// How many sequence numbers of history the change feed keeps, from
+    /// `DB_CHANGE_RETENTION`. Older entries of a table are dropped when the
+    /// table is next written; followers that fall further behind miss them.
+    static ref RETENTION: u64 = env::var("DB_CHANGE_RETENTION").ok().and_then(|v| v.parse().ok()).unwrap_or(10_000);
+}
+
+pub struct Subscriber(String);
+
+impl Subscriber {
+    pub fn new(db: &str) -> Subscriber {
+        *SUBSCRIBERS.lock().unwrap().entry(db.to_string()).or_insert(0) += 1;
+        Subscriber(db.to_string())
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let mut subs = SUBSCRIBERS.lock().unwrap();
+        let done = subs.get_mut(&self.0).map_or(true, |n| {
+            *n -= 1;
+            *n == 0
+        });
+        if done {
+            subs.remove(&self.0);
+        }
+    }
+}
+
+pub fn subscribers(db: &str) -> usize {
+    SUBSCRIBERS.lock().unwrap().get(db).cloned().unwrap_or(0)
+}
+
+fn feed_prefix(table: &str) -> String {
+    format!("^{}/", table)
+}
+
+fn feed_key(table: &str, seq: u64) -> String {
+    format!("{}{:020}", feed_prefix(table), seq)
+}
+
+impl Change {
+    pub fn row(table: &str, kind: ChangeKind, ident: u64, old: Option<&[DBValue]>, new: Option<&[DBValue]>) -> Change {
+        Change {
+            seq: 0,
+            table: table.to_string(),
+            kind,
+            ident: Some(ident),
+            old: old.map(<[DBValue]>::to_vec),
+            new: new.map(<[DBValue]>::to_vec),
+            schema: None,
+        }
+    }
+
+    pub fn table(table: &str, kind: ChangeKind, schema: Option<&Schema>) -> Change {
+        Change {
+            seq: 0,
+            table: table.to_string(),
+            kind,
+            ident: None,
+            old: None,
+            new: None,
+            schema: schema.cloned(),
+        }
+    }
+}
+
+pub fn record<KV: GetSet>(db: &KV, mut change: Change) {
+    let seq = db.get_value::<u64>("^").unwrap_or(0) + 1;
+    db.set_value("^", &seq);
+    change.seq = seq;
+    db.set_value(&feed_key(&change.table, seq), &change);
+    if seq > *RETENTION {
+        prune(db, &change.table, seq - *RETENTION);
+    }
+}
+
+/// Drops the feed entries of `table` up to and including `seq`.
+pub fn prune<KV: GetSet>(db: &KV, table: &str, seq: u64) {
+    let prefix = feed_prefix(table);
+    let last = feed_key(table, seq);
+    loop {
+        match db.scan_keys(&prefix, &prefix, 1).pop() {
+            Some(k) if k <= last => { db.del(&k); },
+            _ => break
+        }
+    }
+}
+
+/// Moves the feed of a renamed table to its new name. Followers of the new
+/// name see its whole history; followers of the old name only see the Drop
+/// recorded after the move.
+pub fn rename<KV: GetSet>(db: &KV, old: &str, new: &str) {
+    for k in db.keys(&feed_prefix(old)) {
+        if let Some(mut c) = db.get_value::<Change>(&k) {
+            c.table = new.to_string();
+            db.set_value(&feed_key(new, c.seq), &c);
+        }
+        db.del(&k);
+    }
+}
+
+pub fn since<KV: GetSet>(db: &KV, table: &str, seq: u64) -> Vec<Change> {
+    db.scan_keys(&feed_prefix(table), &feed_key(table, seq.saturating_add(1)), usize::max_value()).into_iter()
+        .filter_map(|k| db.get_value(&k))
+        .collect()
+}
+
+pub fn notify() {
+    let (lock, cvar) = &*SIGNAL;
+    *lock.lock().unwrap() += 1;
+    cvar.notify_all();
+}
+
+pub fn generation() -> u64 {
+    *SIGNAL.0.lock().unwrap()
+}
+
+pub fn wait(generation: u64, timeout: Duration) {
+    let (lock, cvar) = &*SIGNAL;
+    let guard = lock.lock().unwrap();
+    if *guard == generation {
+        let _ = cvar.wait_timeout(guard, timeout).unwrap();
+    }
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    pub fn changes(&self, table: &str, seq: u64) -> DBResult<Vec<Change>> {
+        let changes = since(&self.tree, table, seq);
+        if changes.is_empty() && !self.tree.has_key(&format!("/{}", table)) && self.tree.scan_keys(&feed_prefix(table), &feed_prefix(table), 1).is_empty() {
+            return Err(TableNotFound);
+        }
+        Ok(changes)
+    }
+}
+
+pub fn poll(db: &str, table: &str, seq: u64, timeout: Duration) -> DBResult<Vec<Change>> {
+    let _sub = Subscriber::new(db);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let gen = generation();
+        let changes = {
+            let mut dbs = DATABASES.lock().unwrap();
+            get_db(&mut *dbs, db)?.changes(table, seq)?
+        };
+        let now = Instant::now();
+        if !changes.is_empty() || now >= deadline {
+            return Ok(changes);
+        }
+        wait(gen, deadline - now);
+    }
+}
+
+pub struct EventStream {
+    db: String,
+    table: String,
+    seq: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+    _sub: Subscriber,
+}
+
+impl EventStream {
+    pub fn new(db: &str, table: &str, seq: u64) -> EventStream {
+        EventStream {
+            db: db.to_string(),
+            table: table.to_string(),
+            seq,
+            buf: vec![],
+            pos: 0,
+            done: false,
+            _sub: Subscriber::new(db),
+        }
+    }
+
+    // Blocks until there are changes or the keepalive is due. Rocket only
+    // writes a chunk once it is full, so every batch is padded with comment
+    // lines to a multiple of CHUNK.
+    fn fill(&mut self) {
+        self.buf.clear();
+        self.pos = 0;
+        match poll(&self.db, &self.table, self.seq, Duration::from_secs(KEEPALIVE)) {
+            Ok(ref changes) if changes.is_empty() => self.buf.extend_from_slice(b": keepalive\n\n"),
+            Ok(changes) => for c in changes {
+                self.seq = c.seq;
+                let data = serde_json::to_string(&c).unwrap();
+                self.buf.extend_from_slice(format!("id: {}\nevent: {:?}\ndata: {}\n\n", c.seq, c.kind, data).as_bytes());
+            },
+            Err(e) => {
+                let data = serde_json::to_string(&e).unwrap();
+                self.buf.extend_from_slice(format!("event: error\ndata: {}\n\n", data).as_bytes());
+                self.done = true;
+            }
+        }
+        let mut pad = (CHUNK - self.buf.len() % CHUNK) % CHUNK;
+        if pad == 1 {
+            pad += CHUNK;
+        }
+        if pad > 0 {
+            self.buf.push(b':');
+            self.buf.resize(self.buf.len() + pad - 2, b' ');
+            self.buf.push(b'\n');
+        }
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill();
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::getset::MemStore;
+
+    fn seqs(changes: Vec<Change>) -> Vec<u64> {
+        changes.into_iter().map(|c| c.seq).collect()
+    }
+
+    #[test]
+    fn since_and_prune() {
+        let db = MemStore::default();
+        for i in 0..6 {
+            let table = if i % 2 == 0 { "a" } else { "b" };
+            record(&db, Change::row(table, ChangeKind::Insert, i, None, None));
+        }
+        assert_eq!(seqs(since(&db, "a", 0)), vec![1, 3, 5]);
+        assert_eq!(seqs(since(&db, "a", 3)), vec![5]);
+        assert_eq!(seqs(since(&db, "b", 6)), Vec::<u64>::new());
+
+        prune(&db, "a", 3);
+        assert_eq!(seqs(since(&db, "a", 0)), vec![5]);
+        assert_eq!(seqs(since(&db, "b", 0)), vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn rename_moves_history() {
+        let db = MemStore::default();
+        record(&db, Change::row("a", ChangeKind::Insert, 1, None, None));
+        record(&db, Change::row("a", ChangeKind::Delete, 1, None, None));
+        rename(&db, "a", "c");
+        assert!(since(&db, "a", 0).is_empty());
+        let moved = since(&db, "c", 0);
+        assert_eq!(seqs(moved.clone()), vec![1, 2]);
+        assert!(moved.iter().all(|c| c.table == "c"));
+    }
+}
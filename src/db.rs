@@ -1,13 +1,29 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::mem::discriminant;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use rand::Rng;
 use serde_derive::{Serialize, Deserialize};
 use sled::Tree;
 use problem::{Problem, ToProblem};
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
 
-use crate::getset::{EasyGet, GetSet};
+use crate::expr::{Expr, Projection, is_true};
+use crate::catalog;
+use crate::changes::{self, Change, ChangeKind};
+use crate::fts;
+use crate::integrity;
+use crate::legacy;
+use crate::matview;
+use crate::migrate::{self, SchemaOp};
+use crate::trigger::{self, Event, Timing, Trigger};
+use crate::getset::{EasyGet, GetSet, Txn};
+#[cfg(test)]
+use crate::getset::MemStore;
+use crate::query::{AggQuery, Query, ResultSet, View, aggregate, project};
 //use getset::{EasyGet, GetSet};
 
 use self::DBError::*;
@@ -15,7 +31,7 @@ use self::DBError::*;
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct DB<KV: GetSet> {
-    tree: KV,
+    pub(crate) tree: KV,
     name: String,
 }
 
@@ -37,17 +53,122 @@ pub enum DBValue {
     Real(f64),
     Str(String),
     StrCI(String),
+    Null,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RefAction {
+    Restrict,
+    Cascade,
+    SetNull,
+}
+
+impl Default for RefAction {
+    fn default() -> RefAction {
+        RefAction::Restrict
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reference {
+    pub table: String,
+    #[serde(default)]
+    pub column: Option<String>,
+    #[serde(default)]
+    pub on_delete: RefAction,
+    #[serde(default)]
+    pub on_update: RefAction,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Column {
     pub name: String,
-    pub ctype: Type
+    pub ctype: Type,
+    #[serde(default)]
+    pub nullable: bool,
+    #[serde(default)]
+    pub references: Option<Reference>,
+    #[serde(default)]
+    pub fulltext: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Schema {
-    pub columns: Vec<Column>
+    pub columns: Vec<Column>,
+    #[serde(default)]
+    pub checks: Vec<Check>,
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub ids: IdGen,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum IdGen {
+    Random,
+    Sequence,
+    Time,
+}
+
+impl Default for IdGen {
+    fn default() -> IdGen {
+        IdGen::Random
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Check {
+    pub name: String,
+    pub expr: String
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Collation {
+    Byte,
+    CaseInsensitive,
+    Unicode,
+}
+
+impl Default for Collation {
+    fn default() -> Collation {
+        Collation::Byte
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FailurePolicy {
+    Abort,
+    Default,
+    Null,
+    Delete,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> FailurePolicy {
+        FailurePolicy::Abort
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RowFailure {
+    pub ident: u64,
+    pub value: DBValue
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ColumnReport {
+    pub rows: usize,
+    pub policy: FailurePolicy,
+    pub failures: Vec<RowFailure>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SortKey {
+    pub column: String,
+    #[serde(default)]
+    pub desc: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToProblem)]
@@ -55,17 +176,45 @@ pub enum DBError {
     OpenError,
     StoreError,
     DatabaseNotFound,
+    InvalidDatabaseName,
+    InvalidTableName,
+    DatabaseExists,
+    DatabaseBusy,
+    ConfirmationRequired,
     TableNotFound,
     TableExists,
     RecordNotFound,
     TypeMismatch,
     InvalidColumn,
     ColumnExists,
-    InvalidPosition
+    InvalidPosition,
+    InvalidReference,
+    ForeignKeyViolation,
+    TableReferenced,
+    ColumnReferenced,
+    InvalidQuery,
+    InvalidExpression,
+    DivisionByZero,
+    Overflow,
+    ReadOnlyView,
+    CheckViolation(String),
+    TriggerAborted(String),
+    LossyRevert,
+    ChecksumMismatch,
+    BackupNotFound,
+    ArchiveTooLarge,
+    SchemaChanged,
 }
 
 pub type DBResult<T> = Result<T, DBError>;
 
+const PROGRESS_STEP: usize = 1000;
+const LAYOUT: u32 = 2;
+const MAX_TABLE_NAME: usize = 128;
+// Characters that start or separate keys in the store.
+const KEY_CHARS: &[char] = &['/', '#', '$', '%', '@', '~', '!', '^', '&', '*', '+', '='];
+pub const ID_COLUMN: &str = "_id";
+
 pub trait ITable {
     fn get_info(&self) -> TableInfo;
     fn add_record(&mut self, value: &[DBValue]) -> DBResult<u64>;
@@ -73,45 +222,76 @@ pub trait ITable {
     fn del_record(&mut self, ident: u64) -> DBResult<()>;
     fn del_record_by_idx(&mut self, idx: u64) -> DBResult<()>;
     fn upd_record_by_idx(&mut self, idx: u64, value: &[DBValue]) -> DBResult<()>;
-    fn sort_records(&self, key: String) -> DBResult<Vec<Record>>;
+    fn sort_records(&self, keys: &[SortKey], collation: Collation) -> DBResult<Vec<Record>>;
     fn get_records(&self) -> Vec<Record>;
+    fn select(&self, projection: &[Projection]) -> DBResult<ResultSet>;
+    fn aggregate(&self, query: &AggQuery) -> DBResult<ResultSet>;
+    fn search(&self, query: &str) -> Vec<SearchHit>;
     fn add_column(&mut self, column: &Column, idx: Option<usize>) -> DBResult<()>;
     fn del_column(&mut self, column: String) -> DBResult<()>;
     fn move_column(&mut self, column: String, idx: usize) -> DBResult<()>;
     fn upd_column(&mut self, old: String, new: &Column) -> DBResult<()>;
+    fn upd_column_with(&mut self, old: String, new: &Column, policy: FailurePolicy, progress: &dyn Fn(usize, usize)) -> DBResult<ColumnReport>;
+    fn column_failures(&self, old: &str, new: &Column) -> DBResult<Vec<RowFailure>>;
+    fn add_trigger(&mut self, trigger: &Trigger) -> DBResult<()>;
+    fn del_trigger(&mut self, name: &str) -> DBResult<()>;
 }
 
 #[derive(Debug)]
-struct Table<'a, KV: GetSet> {
+pub(crate) struct Table<'a, KV: GetSet> {
     pub name: String,
     pub schema: Schema,
+    pub(crate) readonly: bool,
+    pub(crate) depth: usize,
+    pub(crate) reverting: bool,
     records: Vec<u64>,
     db: &'a mut KV
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TableKind {
+    Table,
+    View,
+    MaterializedView,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TableEntry {
+    pub name: String,
+    pub kind: TableKind
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TableStats {
+    pub records: usize,
+    pub bytes: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TableInfo {
-    name: String,
-    schema: Schema
+    pub name: String,
+    pub schema: Schema
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, PartialOrd)]
 pub struct Record {
     pub ident: u64,
-    value: Vec<DBValue>
+    pub value: Vec<DBValue>
 }
 
-lazy_static! {
-    pub static ref DATABASES: Mutex<BTreeMap<String, DB<Tree>>> = Mutex::new(BTreeMap::new());
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchHit {
+    pub record: Record,
+    pub score: f64
 }
 
-pub fn get_dbs() -> Vec<String> {
-    DATABASES.lock().unwrap().keys().cloned().collect()
+lazy_static! {
+    pub static ref DATABASES: Mutex<BTreeMap<String, DB<Tree>>> = Mutex::new(BTreeMap::new());
 }
 
 pub fn get_or_create_db<'a>(dbs: &'a mut BTreeMap<String, DB<Tree>>, name: &str) -> DBResult<&'a mut DB<Tree>> {
     if !dbs.contains_key(name) {
-        dbs.insert(name.to_string(), DB::new(name));
+        dbs.insert(name.to_string(), DB::new(name)?);
     }
     dbs.get_mut(name).ok_or(DatabaseNotFound)
 }
@@ -120,33 +300,138 @@ pub fn get_db<'a>(dbs: &'a mut BTreeMap<String, DB<Tree>>, name: &str) -> DBResu
     dbs.get_mut(name).ok_or(DatabaseNotFound)
 }
 
+fn init<KV: GetSet>(tree: &KV) {
+    if !tree.has_key("/") {
+        let tables: Vec<String> = Vec::new();
+        tree.set_value("/", &tables);
+        tree.set_value("=", &LAYOUT);
+    }
+    upgrade_layout(tree);
+}
+
 impl DB<Tree> {
-    pub fn new(name: &str) -> DB<Tree> {
-        let tree = Tree::start_default(&name).unwrap();
-        if !tree.has_key("/") {
-            let tables: Vec<String> = Vec::new();
-            tree.set_value("/", &tables);
-        }
-        DB {
+    pub fn new(name: &str) -> DBResult<DB<Tree>> {
+        let path = catalog::path(name)?;
+        std::fs::create_dir_all(&*catalog::DATA_DIR).map_err(|_| OpenError)?;
+        let tree = Tree::start_default(path).map_err(|_| OpenError)?;
+        init(&tree);
+        catalog::register(name).map_err(|_| OpenError)?;
+        Ok(DB {
             tree,
             name: String::from(name),
+        })
+    }
+}
+
+#[cfg(test)]
+impl DB<MemStore> {
+    pub fn in_memory(tree: MemStore) -> DB<MemStore> {
+        init(&tree);
+        DB {
+            tree,
+            name: String::from("test"),
         }
     }
 }
 
 impl<KV> DB<KV>
     where KV: GetSet {
+    pub fn atomic<R, F>(&mut self, f: F) -> DBResult<R>
+        where F: FnOnce(&mut DB<Txn<KV>>) -> DBResult<R> {
+        let mut db = DB {
+            tree: Txn::new(&self.tree),
+            name: self.name.clone(),
+        };
+        let res = f(&mut db)?;
+        db.tree.commit();
+        changes::notify();
+        Ok(res)
+    }
+
+    pub fn dry_run<R, F>(&mut self, f: F) -> DBResult<R>
+        where F: FnOnce(&mut DB<Txn<KV>>) -> DBResult<R> {
+        let mut db = DB {
+            tree: Txn::new(&self.tree),
+            name: self.name.clone(),
+        };
+        f(&mut db)
+    }
+
     pub fn get_tables(&self) -> DBResult<Vec<String>> {
         self.tree.get_value("/").ok_or(TableNotFound)
     }
 
+    pub fn get_views(&self) -> Vec<String> {
+        self.tree.get_value("@").unwrap_or_default()
+    }
+
+    pub fn list_tables(&self) -> DBResult<Vec<TableEntry>> {
+        let tables = self.get_tables()?.into_iter()
+            .map(|name| {
+                let kind = if matview::is_matview(&self.tree, &name) { TableKind::MaterializedView } else { TableKind::Table };
+                TableEntry { name, kind }
+            });
+        let views = self.get_views().into_iter()
+            .map(|name| TableEntry { name, kind: TableKind::View });
+        Ok(tables.chain(views).collect())
+    }
+
+    fn name_taken(&self, name: &str) -> bool {
+        self.tree.has_key(&format!("/{}", name)) || self.tree.has_key(&format!("@{}", name))
+    }
+
+    pub fn add_view(&mut self, name: &str, query: &Query) -> DBResult<()> {
+        validate_table_name(name)?;
+        if self.name_taken(name) {
+            return Err(TableExists);
+        }
+        self.run_query(query)?;
+        self.tree.set_value(&format!("@{}", name), query);
+        let mut views = self.get_views();
+        views.push(name.to_string());
+        self.tree.set_value("@", &views);
+        Ok(())
+    }
+
+    pub fn remove_view(&mut self, name: &str, cascade: bool) -> DBResult<()> {
+        let k = format!("@{}", name);
+        if !self.tree.has_key(&k) {
+            return Err(TableNotFound);
+        }
+        let deps = self.view_dependents(name);
+        if !deps.is_empty() && !cascade {
+            return Err(TableReferenced);
+        }
+        for v in deps {
+            self.remove_view(&v, true)?;
+        }
+        let views: Vec<String> = self.get_views().into_iter().filter(|v| v != name).collect();
+        self.tree.set_value("@", &views);
+        self.tree.del(&k);
+        Ok(())
+    }
+
+    fn view_dependents(&self, name: &str) -> Vec<String> {
+        self.get_views().into_iter()
+            .filter(|v| self.tree.get_value::<Query>(&format!("@{}", v))
+                .map_or(false, |q| q.sources().contains(&name)))
+            .collect()
+    }
+
     pub fn add_table(&mut self, name: &str, schema: &Schema) -> DBResult<()> {
-        let k = format!("/{}", name);
-        if self.tree.has_key(&k) {
+        validate_table_name(name)?;
+        if self.name_taken(name) {
             return Err(TableExists);
         }
+        for column in &schema.columns {
+            column.validate()?;
+            check_reference(&self.tree, name, schema, column)?;
+        }
+        schema.validate_checks()?;
+        trigger::validate(schema)?;
         let tab = Table::new(name, schema.clone(), vec![], &mut self.tree);
         tab.update();
+        tab.log_schema();
 
         let mut tv = self.get_tables()?;
         tv.push(name.to_string());
@@ -154,17 +439,59 @@ impl<KV> DB<KV>
         Ok(())
     }
 
-    pub fn remove_table(&mut self, name: &str) -> DBResult<()> {
+    pub fn remove_table(&mut self, name: &str, cascade: bool) -> DBResult<()> {
         let k : String = format!("/{}", name);
         if !self.tree.has_key(&k) {
             return Err(TableNotFound);
         }
 
+        let deps: Vec<_> = dependents(&self.tree, name)?.into_iter()
+            .filter(|(t, _, _)| t != name)
+            .collect();
+        let views = self.view_dependents(name);
+        let matviews = matview::dependents(&self.tree, name);
+        let triggers = trigger::dependents(&self.tree, name)?;
+        if (!deps.is_empty() || !views.is_empty() || !matviews.is_empty() || !triggers.is_empty()) && !cascade {
+            return Err(TableReferenced);
+        }
+        for v in views {
+            if self.tree.has_key(&format!("@{}", v)) {
+                self.remove_view(&v, true)?;
+            }
+        }
+        for m in matviews {
+            if self.tree.has_key(&format!("/{}", m)) {
+                self.remove_table(&m, true)?;
+            }
+        }
+        matview::forget(&self.tree, name);
+        for (t, idx, _) in deps {
+            let sk = format!("#{}", t);
+            let mut schema: Schema = self.tree.get_value(&sk).ok_or(TableNotFound)?;
+            schema.columns[idx].references = None;
+            self.tree.set_value(&sk, &schema);
+        }
+        for (t, trigger) in triggers {
+            let sk = format!("#{}", t);
+            let mut schema: Schema = self.tree.get_value(&sk).ok_or(TableNotFound)?;
+            schema.triggers.retain(|tr| tr.name != trigger);
+            self.tree.set_value(&sk, &schema);
+        }
+        fts::drop_table(&self.tree, name);
+        integrity::forget(&self.tree, name);
+        migrate::forget(&self.tree, name);
+        changes::record(&self.tree, Change::table(name, ChangeKind::Drop, None));
+
         let mut tv = self.get_tables()?;
         let idx = tv.iter().position(|x| *x == name).ok_or(TableNotFound)?;
         tv.remove(idx);
         self.tree.set_value("/", &tv);
 
+        for rk in self.tree.keys(&records_prefix(name)) {
+            self.tree.del(&rk);
+        }
+        self.tree.del(&last_id_key(name));
+        self.tree.del(&format!("#{}", name));
         if self.tree.del(&k) {
             Ok(())
         } else {
@@ -172,10 +499,194 @@ impl<KV> DB<KV>
         }
     }
 
-    pub fn get_table<'a>(&'a mut self, name: &str) -> DBResult<impl ITable + 'a> {
-        let recs = self.tree.get_value(&format!("/{}", name)).ok_or(TableNotFound)?;
-        let schema = self.tree.get_value(&format!("#{}", name)).ok_or(TableNotFound)?;
-        Ok(Table::new(name, schema, recs, &mut self.tree))
+    pub fn rename_table(&mut self, name: &str, new: &str) -> DBResult<()> {
+        if !self.tree.has_key(&format!("/{}", name)) {
+            return Err(TableNotFound);
+        }
+        validate_table_name(new)?;
+        if self.name_taken(new) {
+            return Err(TableExists);
+        }
+        let tables: Vec<String> = self.get_tables()?.into_iter()
+            .map(|t| if t == name { new.to_string() } else { t })
+            .collect();
+        self.tree.set_value("/", &tables);
+        for (from, to) in &[(format!("/{}", name), format!("/{}", new)), (format!("#{}", name), format!("#{}", new)), (last_id_key(name), last_id_key(new))] {
+            if self.tree.has_key(from) {
+                self.tree.set_unsafe(to, self.tree.get_unsafe(from));
+                self.tree.del(from);
+            }
+        }
+        let prefix = records_prefix(name);
+        for k in self.tree.keys(&prefix) {
+            self.tree.set_unsafe(&format!("{}{}", records_prefix(new), &k[prefix.len()..]), self.tree.get_unsafe(&k));
+            self.tree.del(&k);
+        }
+        for t in &tables {
+            let sk = format!("#{}", t);
+            let mut schema: Schema = self.tree.get_value(&sk).ok_or(TableNotFound)?;
+            for c in &mut schema.columns {
+                match &mut c.references {
+                    Some(r) if r.table == name => r.table = new.to_string(),
+                    _ => ()
+                }
+            }
+            for tr in &mut schema.triggers {
+                tr.retarget(name, new);
+            }
+            self.tree.set_value(&sk, &schema);
+        }
+        for v in self.view_dependents(name) {
+            let k = format!("@{}", v);
+            let mut query: Query = self.tree.get_value(&k).ok_or(TableNotFound)?;
+            query.retarget(name, new);
+            self.tree.set_value(&k, &query);
+        }
+        matview::rename(&self.tree, name, new);
+        fts::rename_table(&self.tree, name, new);
+        integrity::rename(&self.tree, name, new);
+        migrate::rename(&self.tree, name, new);
+        changes::rename(&self.tree, name, new);
+        changes::record(&self.tree, Change::table(name, ChangeKind::Drop, None));
+        Table::load(new, &mut self.tree)?.log_schema();
+        Ok(())
+    }
+
+    pub fn copy_table(&mut self, name: &str, new: &str, data: bool, filter: Option<&Expr>) -> DBResult<usize> {
+        let (schema, records) = {
+            let table = self.get_table(name)?;
+            (table.get_info().schema, if data { table.get_records() } else { vec![] })
+        };
+        let schema = Schema { columns: schema.columns, checks: schema.checks, ids: schema.ids, ..Schema::default() };
+        self.add_table(new, &schema)?;
+        let names: Vec<String> = schema.columns.iter().map(|c| c.name.clone()).collect();
+        let mut copy = Table::load(new, &mut self.tree)?;
+        let mut count = 0;
+        for Record { value, .. } in records {
+            let keep = match filter {
+                Some(f) => is_true(&f.eval(&names, &value)?),
+                None => true
+            };
+            if keep {
+                copy.add_record(&value)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn set_id_generator(&mut self, name: &str, ids: IdGen) -> DBResult<()> {
+        let mut table = Table::load(name, &mut self.tree)?;
+        table.writable()?;
+        table.schema.ids = ids;
+        table.update();
+        table.log_schema();
+        Ok(())
+    }
+
+    pub fn table_stats(&self, name: &str) -> DBResult<TableStats> {
+        if !self.tree.has_key(&format!("/{}", name)) {
+            return Err(TableNotFound);
+        }
+        let keys = self.tree.keys(&records_prefix(name));
+        let bytes = keys.iter().map(|k| self.tree.get_unsafe(k).len()).sum();
+        Ok(TableStats { records: keys.len(), bytes })
+    }
+
+    pub fn get_table<'a>(&'a mut self, name: &str) -> DBResult<Box<dyn ITable + 'a>> {
+        if let Some(query) = self.tree.get_value::<Query>(&format!("@{}", name)) {
+            let (schema, res) = self.run_query(&query)?;
+            return Ok(Box::new(View::new(name, schema, res)));
+        }
+        Ok(Box::new(Table::load(name, &mut self.tree)?))
+    }
+}
+
+pub fn validate_table_name(name: &str) -> DBResult<()> {
+    if name.is_empty() || name.len() > MAX_TABLE_NAME || name.contains(KEY_CHARS) || name.chars().any(char::is_control) {
+        return Err(InvalidTableName);
+    }
+    Ok(())
+}
+
+pub fn record_key(table: &str, ident: u64) -> String {
+    format!("${}/{}", table, ident)
+}
+
+pub fn records_prefix(table: &str) -> String {
+    format!("${}/", table)
+}
+
+pub(crate) fn last_id_key(table: &str) -> String {
+    format!("+{}", table)
+}
+
+// Layout 1 keys rows by table, layout 2 stores every schema, migration and
+// change entry in the current shape.
+fn upgrade_layout<KV: GetSet>(db: &KV) {
+    let layout = db.get_value::<u32>("=").unwrap_or(0);
+    if layout >= LAYOUT {
+        return;
+    }
+    if layout < 1 {
+        let tables: Vec<String> = db.get_value("/").unwrap_or_default();
+        for t in &tables {
+            let records: Vec<u64> = db.get_value(&format!("/{}", t)).unwrap_or_default();
+            for ident in records {
+                let old = format!("${}", ident);
+                if db.has_key(&old) {
+                    db.set_unsafe(&record_key(t, ident), db.get_unsafe(&old));
+                }
+            }
+        }
+        for k in db.keys("$") {
+            if !k.contains('/') {
+                db.del(&k);
+            }
+        }
+    }
+    if layout < 2 {
+        legacy::upgrade_schemas(db);
+        legacy::upgrade_entries(db);
+    }
+    db.set_value("=", &LAYOUT);
+}
+
+fn dependents<KV: GetSet>(db: &KV, table: &str) -> DBResult<Vec<(String, usize, Reference)>> {
+    let tables: Vec<String> = db.get_value("/").ok_or(TableNotFound)?;
+    let mut deps = vec![];
+    for t in tables {
+        let schema: Schema = db.get_value(&format!("#{}", t)).ok_or(TableNotFound)?;
+        for (idx, c) in schema.columns.into_iter().enumerate() {
+            if let Some(r) = c.references {
+                if r.table == table {
+                    deps.push((t.clone(), idx, r));
+                }
+            }
+        }
+    }
+    Ok(deps)
+}
+
+pub(crate) fn check_reference<KV: GetSet>(db: &KV, table: &str, schema: &Schema, column: &Column) -> DBResult<()> {
+    let r = match &column.references {
+        Some(r) => r,
+        None => return Ok(())
+    };
+    let target = if r.table == table {
+        schema.clone()
+    } else {
+        db.get_value(&format!("#{}", r.table)).ok_or(InvalidReference)?
+    };
+    let ok = match &r.column {
+        None => column.ctype == Type::Integer,
+        Some(c) => target.columns.iter().any(|tc| tc.name == *c && tc.ctype.is_subtype(&column.ctype)),
+    };
+    let null_ok = column.nullable || (r.on_delete != RefAction::SetNull && r.on_update != RefAction::SetNull);
+    if ok && null_ok {
+        Ok(())
+    } else {
+        Err(InvalidReference)
     }
 }
 
@@ -185,15 +696,193 @@ impl<'a, T> Table<'a, T>
         Table {
             name: name.to_string(),
             schema,
+            readonly: false,
+            depth: 0,
+            reverting: false,
             records,
             db
         }
     }
 
+    pub(crate) fn load(name: &str, db: &'a mut T) -> DBResult<Table<'a, T>> {
+        let recs = db.get_value(&format!("/{}", name)).ok_or(TableNotFound)?;
+        let schema = db.get_value(&format!("#{}", name)).ok_or(TableNotFound)?;
+        let readonly = matview::is_matview(&*db, name);
+        Ok(Table { readonly, ..Table::new(name, schema, recs, db) })
+    }
+
+    fn fire(&mut self, timing: Timing, event: Event, old: Option<&[DBValue]>, new: Option<&[DBValue]>) -> DBResult<()> {
+        if self.schema.triggers.is_empty() {
+            return Ok(());
+        }
+        trigger::fire(&mut *self.db, &self.schema, timing, event, old, new, self.depth)?;
+        self.reload();
+        Ok(())
+    }
+
+    fn next_id(&self) -> DBResult<u64> {
+        let lk = last_id_key(&self.name);
+        let last: u64 = self.db.get_value(&lk).unwrap_or(0);
+        let mut k = match self.schema.ids {
+            IdGen::Random => rand::thread_rng().gen(),
+            IdGen::Sequence => last.checked_add(1).ok_or(Overflow)?,
+            IdGen::Time => {
+                let ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() * 1000 + u64::from(d.subsec_millis()));
+                ((ms << 16) | u64::from(rand::thread_rng().gen::<u16>())).max(last.checked_add(1).ok_or(Overflow)?)
+            }
+        };
+        while self.db.has_key(&record_key(&self.name, k)) {
+            k = match self.schema.ids {
+                IdGen::Random => rand::thread_rng().gen(),
+                _ => k.checked_add(1).ok_or(Overflow)?
+            };
+        }
+        if self.schema.ids != IdGen::Random {
+            self.db.set_value(&lk, &k);
+        }
+        Ok(k)
+    }
+
+    fn writable(&self) -> DBResult<()> {
+        if self.readonly {
+            Err(ReadOnlyView)
+        } else {
+            Ok(())
+        }
+    }
+
     fn update(&self) {
         self.db.set_value(&format!("/{}", self.name), &self.records);
         self.db.set_value(&format!("#{}", self.name), &self.schema);
     }
+
+    fn migrated(&mut self, before: Schema, op: SchemaOp, saved: BTreeMap<u64, DBValue>) {
+        if self.reverting {
+            self.update();
+            return;
+        }
+        self.schema.version = before.version + 1;
+        self.update();
+        migrate::record(&*self.db, &self.name, self.schema.version, op, before, saved);
+        self.log_schema();
+        matview::invalidate(&*self.db, &self.name);
+    }
+
+    pub(crate) fn reverted(&mut self, version: u64) {
+        self.schema.version = version;
+        self.update();
+        self.log_schema();
+        matview::invalidate(&*self.db, &self.name);
+    }
+
+    pub(crate) fn restore_values(&mut self, idx: usize, values: &BTreeMap<u64, DBValue>) -> DBResult<()> {
+        let column = self.schema.columns.get(idx).cloned().ok_or(InvalidColumn)?;
+        for Record { ident, mut value } in self.get_records() {
+            if let Some(v) = values.get(&ident) {
+                if column.fulltext {
+                    fts::index_value(&*self.db, &self.name, &column.name, ident, &value[idx], false);
+                    fts::index_value(&*self.db, &self.name, &column.name, ident, v, true);
+                }
+                value[idx] = v.clone();
+                self.db.set_value(&record_key(&self.name, ident), &value);
+            }
+        }
+        Ok(())
+    }
+
+    fn log_schema(&self) {
+        changes::record(&*self.db, Change::table(&self.name, ChangeKind::Schema, Some(&self.schema)));
+    }
+
+    fn result_set(&self) -> ResultSet {
+        ResultSet::from_records(&self.schema, self.get_records())
+    }
+
+    fn reload(&mut self) {
+        self.records = self.db.get_value(&format!("/{}", self.name)).unwrap_or_default();
+    }
+
+    fn key_of(&self, column: &Option<String>, ident: u64, value: &[DBValue]) -> DBResult<DBValue> {
+        match column {
+            None => Ok(DBValue::Integer(ident as i64)),
+            Some(c) => {
+                let idx = self.schema.columns.iter().position(|x| x.name == *c).ok_or(InvalidReference)?;
+                Ok(value[idx].clone())
+            }
+        }
+    }
+
+    fn has_key_value(&self, column: &Option<String>, key: &DBValue) -> DBResult<bool> {
+        match (column, key) {
+            (None, DBValue::Integer(i)) => Ok(self.records.contains(&(*i as u64))),
+            (None, _) => Ok(false),
+            (Some(_), _) => {
+                for Record { ident, value } in self.get_records() {
+                    if self.key_of(column, ident, &value)? == *key {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    fn is_referenced(&self, column: &str) -> DBResult<bool> {
+        Ok(dependents(&*self.db, &self.name)?.iter()
+            .any(|(_, _, r)| r.column.as_ref().map_or(false, |c| c == column)))
+    }
+
+    pub(crate) fn check_references(&mut self, value: &[DBValue]) -> DBResult<()> {
+        let columns = self.schema.columns.clone();
+        for (c, v) in columns.iter().zip(value) {
+            if let (Some(r), false) = (&c.references, *v == DBValue::Null) {
+                let target = Table::load(&r.table, &mut *self.db)?;
+                if !target.has_key_value(&r.column, v)? {
+                    return Err(ForeignKeyViolation);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn propagate(&mut self, ident: u64, old: &[DBValue], new: Option<&[DBValue]>) -> DBResult<()> {
+        for (t, idx, r) in dependents(&*self.db, &self.name)? {
+            let key = self.key_of(&r.column, ident, old)?;
+            let newkey = match new {
+                Some(v) => Some(self.key_of(&r.column, ident, v)?),
+                None => None
+            };
+            if newkey.as_ref() == Some(&key) {
+                continue;
+            }
+            let action = if new.is_some() { r.on_update } else { r.on_delete };
+            let mut dep = Table::load(&t, &mut *self.db)?;
+            dep.depth = self.depth;
+            let hits: Vec<Record> = dep.get_records().into_iter()
+                .filter(|rec| rec.value[idx] == key)
+                .collect();
+            for Record { ident, mut value } in hits {
+                let res = match (action, &newkey) {
+                    (RefAction::Restrict, _) => Err(ForeignKeyViolation),
+                    (RefAction::Cascade, None) => dep.del_record(ident),
+                    (RefAction::Cascade, Some(k)) => {
+                        value[idx] = k.clone();
+                        dep.upd_record(ident, &value)
+                    },
+                    (RefAction::SetNull, _) => {
+                        value[idx] = DBValue::Null;
+                        dep.upd_record(ident, &value)
+                    },
+                };
+                match res {
+                    Err(RecordNotFound) => (),
+                    r => r?
+                }
+            }
+        }
+        self.reload();
+        Ok(())
+    }
 }
 
 impl<'a, KV> ITable for Table<'a, KV>
@@ -206,35 +895,64 @@ impl<'a, KV> ITable for Table<'a, KV>
     }
 
     fn add_record(&mut self, value: &[DBValue]) -> DBResult<u64> {
+        self.writable()?;
         if !self.schema.match_record(value) {
             return Err(TypeMismatch);
         }
-        let mut k: u64 = rand::thread_rng().gen();
-        while self.db.has_key(&format!("${}", k)) {
-            k = rand::thread_rng().gen();
-        };
-        self.db.set_value(&format!("${}", k), &value.to_vec());
+        self.schema.check_record(value)?;
+        self.check_references(value)?;
+        self.fire(Timing::Before, Event::Insert, None, Some(value))?;
+        let k = self.next_id()?;
+        self.db.set_value(&record_key(&self.name, k), &value.to_vec());
+        fts::index_row(&*self.db, &self.name, &self.schema, k, value, true);
         self.records.push(k);
         self.update();
+        matview::maintain(&mut *self.db, &self.name, k, None, Some(value))?;
+        changes::record(&*self.db, Change::row(&self.name, ChangeKind::Insert, k, None, Some(value)));
+        self.fire(Timing::After, Event::Insert, None, Some(value))?;
         Ok(k)
     }
 
     fn upd_record(&mut self, ident: u64, value: &[DBValue]) -> DBResult<()> {
+        self.writable()?;
         self.records.iter().find(|idx| **idx == ident).ok_or(RecordNotFound)?;
         if !self.schema.match_record(value) {
             return Err(TypeMismatch);
         }
-        self.db.set_value(&format!("${}", ident), &value.to_vec());
-        Ok(())
+        self.schema.check_record(value)?;
+        self.check_references(value)?;
+        let k = record_key(&self.name, ident);
+        let old: Vec<DBValue> = self.db.get_value(&k).ok_or(RecordNotFound)?;
+        self.fire(Timing::Before, Event::Update, Some(&old), Some(value))?;
+        // A before trigger may have deleted or changed the row.
+        if !self.records.contains(&ident) {
+            return Err(RecordNotFound);
+        }
+        let old: Vec<DBValue> = self.db.get_value(&k).ok_or(RecordNotFound)?;
+        self.db.set_value(&k, &value.to_vec());
+        fts::index_row(&*self.db, &self.name, &self.schema, ident, &old, false);
+        fts::index_row(&*self.db, &self.name, &self.schema, ident, value, true);
+        self.propagate(ident, &old, Some(value))?;
+        matview::maintain(&mut *self.db, &self.name, ident, Some(&old), Some(value))?;
+        changes::record(&*self.db, Change::row(&self.name, ChangeKind::Update, ident, Some(&old), Some(value)));
+        self.fire(Timing::After, Event::Update, Some(&old), Some(value))
     }
 
     fn del_record(&mut self, ident: u64) -> DBResult<()> {
+        self.writable()?;
+        self.records.iter().find(|idx| **idx == ident).ok_or(RecordNotFound)?;
+        let k = record_key(&self.name, ident);
+        let old: Vec<DBValue> = self.db.get_value(&k).ok_or(RecordNotFound)?;
+        self.fire(Timing::Before, Event::Delete, Some(&old), None)?;
         let idx = self.records.iter().position(|x| *x == ident).ok_or(RecordNotFound)?;
         self.records.remove(idx);
         self.update();
-        let k = format!("${}", ident);
         self.db.del(&k);
-        Ok(())
+        fts::index_row(&*self.db, &self.name, &self.schema, ident, &old, false);
+        self.propagate(ident, &old, None)?;
+        matview::maintain(&mut *self.db, &self.name, ident, Some(&old), None)?;
+        changes::record(&*self.db, Change::row(&self.name, ChangeKind::Delete, ident, Some(&old), None));
+        self.fire(Timing::After, Event::Delete, Some(&old), None)
     }
 
     fn del_record_by_idx(&mut self, idx: u64) -> DBResult<()> {
@@ -247,23 +965,39 @@ impl<'a, KV> ITable for Table<'a, KV>
         self.upd_record(*rid, value)
     }
 
-    fn sort_records(&self, key: String) -> DBResult<Vec<Record>> {
-        let idx = self.schema.columns.iter().position(|c| (*c).name == key).ok_or(InvalidColumn)?; 
-        let mut records = self.get_records();
-        records.sort_by(|a, b| a.value[idx].partial_cmp(&b.value[idx]).unwrap());
-        Ok(records)
+    fn sort_records(&self, keys: &[SortKey], collation: Collation) -> DBResult<Vec<Record>> {
+        sort_by_keys(&self.schema, self.get_records(), keys, collation)
     }
 
     fn get_records(&self) -> Vec<Record> {
+        // A row listed without a value is reported by the integrity check.
         self.records.iter()
-            .map(|idx| Record {
-                ident: *idx,
-                value: self.db.get_value(&format!("${}", idx)).unwrap()
-            })
+            .filter_map(|idx| self.db.get_value(&record_key(&self.name, *idx))
+                .map(|value| Record { ident: *idx, value }))
             .collect::<Vec<_>>()
     }
 
+    fn select(&self, projection: &[Projection]) -> DBResult<ResultSet> {
+        project(&self.result_set(), projection)
+    }
+
+    fn aggregate(&self, query: &AggQuery) -> DBResult<ResultSet> {
+        aggregate(&self.result_set(), query)
+    }
+
+    fn search(&self, query: &str) -> Vec<SearchHit> {
+        // Postings can outlive their row if it was removed outside the table.
+        fts::search(&*self.db, &self.name, &self.schema, self.records.len(), query).into_iter()
+            .filter_map(|(ident, score)| {
+                let value = self.db.get_value(&record_key(&self.name, ident))?;
+                Some(SearchHit { record: Record { ident, value }, score })
+            })
+            .collect()
+    }
+
     fn add_column(&mut self, column: &Column, idx: Option<usize>) -> DBResult<()> {
+        self.writable()?;
+        let before = self.schema.clone();
         let cur_idx = self.schema.columns.iter().position(|c| (*c).name == column.name);
         let idx = idx.unwrap_or_else(|| self.schema.columns.len());
         if cur_idx.is_some() {
@@ -272,101 +1006,415 @@ impl<'a, KV> ITable for Table<'a, KV>
         if idx > self.schema.columns.len() {
             return Err(InvalidPosition);
         }
-        let val = column.ctype.defvalue();
+        column.validate()?;
+        check_reference(&*self.db, &self.name, &self.schema, column)?;
+        let val = if column.nullable { DBValue::Null } else { column.ctype.defvalue() };
+        if let (Some(r), false) = (&column.references, self.records.is_empty() || val == DBValue::Null) {
+            if !Table::load(&r.table, &mut *self.db)?.has_key_value(&r.column, &val)? {
+                return Err(ForeignKeyViolation);
+            }
+        }
         self.schema.columns.insert(idx, column.clone());
         for Record { ident, mut value } in self.get_records() {
             value.insert(idx, val.clone());
-            self.db.set_value(&format!("${}", ident), &value);
+            self.schema.check_record(&value)?;
+            self.db.set_value(&record_key(&self.name, ident), &value);
         }
-        self.update();
+        self.migrated(before, SchemaOp::AddColumn { column: column.clone(), index: Some(idx) }, BTreeMap::new());
         Ok(())
     }
 
     fn del_column(&mut self, column: String) -> DBResult<()> {
+        self.writable()?;
         let idx = self.schema.columns.iter().position(|c| (*c).name == column).ok_or(InvalidColumn)?;
+        if self.is_referenced(&column)? || self.schema.checks_column(&column) {
+            return Err(ColumnReferenced);
+        }
+        if self.schema.columns[idx].fulltext {
+            fts::drop_column(&*self.db, &self.name, &column);
+        }
+        let before = self.schema.clone();
+        let mut saved = BTreeMap::new();
         self.schema.columns.remove(idx);
         for Record { ident, mut value } in self.get_records() {
-            value.remove(idx);
-            self.db.set_value(&format!("${}", ident), &value);
+            saved.insert(ident, value.remove(idx));
+            self.db.set_value(&record_key(&self.name, ident), &value);
         }
-        self.update();
+        self.migrated(before, SchemaOp::DelColumn { column }, saved);
         Ok(())
     }
 
     fn move_column(&mut self, column: String, idx: usize) -> DBResult<()> {
+        self.writable()?;
         let old_idx = self.schema.columns.iter().position(|c| (*c).name == column).ok_or(InvalidColumn)?;
         if idx > self.schema.columns.len() {
             return Err(InvalidPosition);
         }
+        let before = self.schema.clone();
         let c = self.schema.columns.remove(old_idx);
         self.schema.columns.insert(idx, c);
         for Record { ident, mut value } in self.get_records() {
             let v = value.remove(old_idx);
             value.insert(idx, v);
-            self.db.set_value(&format!("${}", ident), &value);
+            self.db.set_value(&record_key(&self.name, ident), &value);
         }
-        self.update();
+        self.migrated(before, SchemaOp::MoveColumn { column, index: idx }, BTreeMap::new());
         Ok(())
     }
 
     fn upd_column(&mut self, old: String, new: &Column) -> DBResult<()> {
+        self.upd_column_with(old, new, FailurePolicy::Abort, &|_, _| ()).map(|_| ())
+    }
+
+    fn column_failures(&self, old: &str, new: &Column) -> DBResult<Vec<RowFailure>> {
+        let idx = self.schema.columns.iter().position(|c| (*c).name == old).ok_or(InvalidColumn)?;
+        Ok(self.get_records().into_iter()
+            .filter(|r| new.convert(&r.value[idx]).is_none())
+            .map(|r| RowFailure { ident: r.ident, value: r.value[idx].clone() })
+            .collect())
+    }
+
+    fn upd_column_with(&mut self, old: String, new: &Column, policy: FailurePolicy, progress: &dyn Fn(usize, usize)) -> DBResult<ColumnReport> {
+        self.writable()?;
         let idx = self.schema.columns.iter().position(|c| (*c).name == old).ok_or(InvalidColumn)?;
         let nidx = self.schema.columns.iter().position(|c| (*c).name == new.name);
         if nidx.is_some() && new.name != old {
             return Err(ColumnExists);
         }
+        if new.name != old && (self.is_referenced(&old)? || self.schema.checks_column(&old)) {
+            return Err(ColumnReferenced);
+        }
+        new.validate()?;
+        check_reference(&*self.db, &self.name, &self.schema, new)?;
+        let failures = self.column_failures(&old, new)?;
+        match (policy, failures.is_empty()) {
+            (_, true) => (),
+            (FailurePolicy::Abort, false) => return Err(TypeMismatch),
+            (FailurePolicy::Null, false) if !new.nullable => return Err(TypeMismatch),
+            (FailurePolicy::Delete, false) => {
+                for f in &failures {
+                    match self.del_record(f.ident) {
+                        Err(RecordNotFound) => (),
+                        r => r?
+                    }
+                }
+            },
+            _ => ()
+        }
         let recs = self.get_records();
-        let mut newrs = Vec::with_capacity(recs.len());
-        for Record { ident, value } in recs {
+        let total = recs.len();
+        let saved: BTreeMap<u64, DBValue> = recs.iter().map(|r| (r.ident, r.value[idx].clone())).collect();
+        let mut newrs = Vec::with_capacity(total);
+        for (i, Record { ident, value }) in recs.into_iter().enumerate() {
             let mut newr = value.clone();
             let v = newr.remove(idx);
-            let val = v.coerce(&new.ctype).ok_or(TypeMismatch)?;
+            let val = match (new.convert(&v), policy) {
+                (Some(val), _) => val,
+                (None, FailurePolicy::Default) => new.ctype.defvalue(),
+                (None, FailurePolicy::Null) => DBValue::Null,
+                (None, _) => return Err(TypeMismatch)
+            };
             newr.insert(idx, val);
+            self.schema.check_record(&newr)?;
             newrs.push(Record {ident, value: newr});
+            if i % PROGRESS_STEP == 0 {
+                progress(i, total);
+            }
+        }
+        if let Some(r) = &new.references {
+            let target = Table::load(&r.table, &mut *self.db)?;
+            for Record { value, .. } in &newrs {
+                if value[idx] != DBValue::Null && !target.has_key_value(&r.column, &value[idx])? {
+                    return Err(ForeignKeyViolation);
+                }
+            }
+        }
+        if self.schema.columns[idx].fulltext {
+            fts::drop_column(&*self.db, &self.name, &old);
         }
         for Record { ident, value } in newrs {
-            self.db.set_value(&format!("${}", ident), &value);
+            if new.fulltext {
+                fts::index_value(&*self.db, &self.name, &new.name, ident, &value[idx], true);
+            }
+            self.db.set_value(&record_key(&self.name, ident), &value);
         }
+        let before = self.schema.clone();
         self.schema.columns.remove(idx);
         self.schema.columns.insert(idx, new.clone());
+        self.migrated(before, SchemaOp::UpdColumn { column: old, new: new.clone() }, saved);
+        progress(total, total);
+        Ok(ColumnReport { rows: total, policy, failures })
+    }
+
+    fn add_trigger(&mut self, trigger: &Trigger) -> DBResult<()> {
+        self.writable()?;
+        if self.schema.triggers.iter().any(|t| t.name == trigger.name) {
+            return Err(InvalidQuery);
+        }
+        trigger.validate()?;
+        for t in trigger.actions.iter().filter_map(|a| a.table()) {
+            if !self.db.has_key(&format!("/{}", t)) {
+                return Err(TableNotFound);
+            }
+        }
+        self.schema.triggers.push(trigger.clone());
+        self.update();
+        self.log_schema();
+        Ok(())
+    }
+
+    fn del_trigger(&mut self, name: &str) -> DBResult<()> {
+        self.writable()?;
+        let idx = self.schema.triggers.iter().position(|t| t.name == name).ok_or(InvalidQuery)?;
+        self.schema.triggers.remove(idx);
         self.update();
+        self.log_schema();
         Ok(())
     }
 }
 
+pub fn sort_by_keys(schema: &Schema, mut records: Vec<Record>, keys: &[SortKey], collation: Collation) -> DBResult<Vec<Record>> {
+    let keys = keys.iter()
+        .map(|k| match schema.columns.iter().position(|c| c.name == k.column) {
+            Some(idx) => Some((Some(idx), k.desc)),
+            None if k.column == ID_COLUMN => Some((None, k.desc)),
+            None => None
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or(InvalidColumn)?;
+    records.sort_by(|a, b| {
+        keys.iter()
+            .map(|&(idx, desc)| {
+                let o = match idx {
+                    Some(idx) => a.value[idx].total_cmp(&b.value[idx], collation),
+                    None => a.ident.cmp(&b.ident)
+                };
+                if desc { o.reverse() } else { o }
+            })
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.ident.cmp(&b.ident))
+    });
+    Ok(records)
+}
+
+impl IdGen {
+    pub fn parse(s: &str) -> DBResult<IdGen> {
+        match s.to_lowercase().as_str() {
+            "random" => Ok(IdGen::Random),
+            "sequence" => Ok(IdGen::Sequence),
+            "time" => Ok(IdGen::Time),
+            _ => Err(InvalidQuery)
+        }
+    }
+}
+
+impl FailurePolicy {
+    pub fn parse(s: &str) -> DBResult<FailurePolicy> {
+        match s.to_lowercase().as_str() {
+            "abort" => Ok(FailurePolicy::Abort),
+            "default" => Ok(FailurePolicy::Default),
+            "null" => Ok(FailurePolicy::Null),
+            "delete" => Ok(FailurePolicy::Delete),
+            _ => Err(InvalidQuery)
+        }
+    }
+}
+
+impl Collation {
+    pub fn parse(s: &str) -> DBResult<Collation> {
+        match s.to_lowercase().as_str() {
+            "byte" => Ok(Collation::Byte),
+            "ci" | "case_insensitive" => Ok(Collation::CaseInsensitive),
+            "unicode" => Ok(Collation::Unicode),
+            _ => Err(InvalidQuery)
+        }
+    }
+
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Byte => a.cmp(b),
+            Collation::CaseInsensitive => a.bytes().map(|c| c.to_ascii_lowercase())
+                .cmp(b.bytes().map(|c| c.to_ascii_lowercase()))
+                .then_with(|| a.cmp(b)),
+            Collation::Unicode => fold(a).cmp(&fold(b)).then_with(|| a.cmp(b)),
+        }
+    }
+}
+
+pub fn fold(s: &str) -> String {
+    s.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+impl SortKey {
+    pub fn parse_list(s: &str) -> DBResult<Vec<SortKey>> {
+        s.split(',')
+            .map(|k| {
+                let mut parts = k.trim().rsplitn(2, ':');
+                let (last, first) = (parts.next().unwrap_or(""), parts.next());
+                match (first, last.to_lowercase().as_str()) {
+                    (Some(c), "asc") => Ok(SortKey { column: c.to_string(), desc: false }),
+                    (Some(c), "desc") => Ok(SortKey { column: c.to_string(), desc: true }),
+                    (None, _) if !last.is_empty() => Ok(SortKey { column: last.to_string(), desc: false }),
+                    _ => Err(InvalidQuery)
+                }
+            })
+            .collect()
+    }
+}
+
 impl Schema {
     pub fn match_record(&self, values: &[DBValue]) -> bool {
         if values.len() != self.columns.len() {
             return false;
         };
         values.iter()
-            .map(DBValue::get_type)
-            .zip(self.column_types())
-            .all(|(sub, sup)| sub.is_subtype(&sup))
+            .zip(&self.columns)
+            .all(|(v, c)| c.accepts(v))
+    }
+
+    pub fn validate_checks(&self) -> DBResult<()> {
+        for (i, check) in self.checks.iter().enumerate() {
+            if self.checks[..i].iter().any(|c| c.name == check.name) {
+                return Err(InvalidQuery);
+            }
+            for c in check.parse()?.columns() {
+                if !self.columns.iter().any(|x| x.name == c) {
+                    return Err(InvalidColumn);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_record(&self, values: &[DBValue]) -> DBResult<()> {
+        let columns: Vec<String> = self.columns.iter().map(|c| c.name.clone()).collect();
+        for check in &self.checks {
+            match check.parse()?.eval(&columns, values)? {
+                DBValue::Null => (),
+                ref v if is_true(v) => (),
+                _ => return Err(CheckViolation(check.name.clone()))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn checks_column(&self, column: &str) -> bool {
+        self.checks.iter()
+            .any(|c| c.parse().map_or(false, |e| e.columns().contains(&column)))
+    }
+}
+
+impl Check {
+    pub fn parse(&self) -> DBResult<Expr> {
+        Expr::parse(&self.expr)
+    }
+}
+
+impl Column {
+    pub fn new(name: &str, ctype: Type) -> Column {
+        Column {
+            name: name.to_string(),
+            ctype,
+            nullable: false,
+            references: None,
+            fulltext: false
+        }
+    }
+
+    pub fn validate(&self) -> DBResult<()> {
+        if self.name.is_empty() || self.name.contains('/') || self.name == ID_COLUMN {
+            return Err(InvalidColumn);
+        }
+        match (self.fulltext, &self.ctype) {
+            (false, _) | (true, Type::Str) | (true, Type::StrCI(_, _)) => Ok(()),
+            _ => Err(InvalidColumn)
+        }
     }
 
-    fn column_types(&self) -> impl Iterator<Item = &Type> {
-        self.columns.iter().map(|c| &c.ctype)
+    pub fn convert(&self, value: &DBValue) -> Option<DBValue> {
+        value.coerce(&self.ctype).filter(|v| self.accepts(v))
+    }
+
+    pub fn accepts(&self, value: &DBValue) -> bool {
+        match value.get_type() {
+            Some(t) => t.is_subtype(&self.ctype),
+            None => self.nullable
+        }
     }
 }
 
 impl DBValue {
-    pub fn get_type(&self) -> Type {
+    pub fn get_type(&self) -> Option<Type> {
         match self {
-            DBValue::Integer(_) => Type::Integer,
-            DBValue::Char(_) => Type::Char,
-            DBValue::CharInvl(c) => Type::CharInvl(*c, *c),
-            DBValue::Real(_) => Type::Real,
-            DBValue::Str(_) => Type::Str,
+            DBValue::Integer(_) => Some(Type::Integer),
+            DBValue::Char(_) => Some(Type::Char),
+            DBValue::CharInvl(c) => Some(Type::CharInvl(*c, *c)),
+            DBValue::Real(_) => Some(Type::Real),
+            DBValue::Str(_) => Some(Type::Str),
             DBValue::StrCI(s) =>
-                Type::StrCI(
+                Some(Type::StrCI(
                     s.chars().min().unwrap_or_else(|| '\0'),
-                    s.chars().max().unwrap_or_else(|| '\0')),
+                    s.chars().max().unwrap_or_else(|| '\0'))),
+            DBValue::Null => None,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            DBValue::Null => 0,
+            DBValue::Integer(_) | DBValue::Real(_) => 1,
+            DBValue::Char(_) | DBValue::CharInvl(_) => 2,
+            DBValue::Str(_) | DBValue::StrCI(_) => 3,
+        }
+    }
+
+    fn as_real(&self) -> Option<f64> {
+        match self {
+            DBValue::Integer(i) => Some(*i as f64),
+            DBValue::Real(f) => Some(*f),
+            _ => None
+        }
+    }
+
+    pub fn total_cmp(&self, other: &DBValue, collation: Collation) -> Ordering {
+        match (self, other) {
+            (DBValue::Integer(a), DBValue::Integer(b)) => a.cmp(b),
+            (DBValue::Char(a), DBValue::Char(b)) | (DBValue::Char(a), DBValue::CharInvl(b)) |
+                (DBValue::CharInvl(a), DBValue::Char(b)) | (DBValue::CharInvl(a), DBValue::CharInvl(b)) => a.cmp(b),
+            (DBValue::Str(a), DBValue::Str(b)) => collation.compare(a, b),
+            (DBValue::Str(a), DBValue::StrCI(b)) | (DBValue::StrCI(a), DBValue::Str(b)) |
+                (DBValue::StrCI(a), DBValue::StrCI(b)) => {
+                let collation = if collation == Collation::Byte { Collation::CaseInsensitive } else { collation };
+                collation.compare(a, b)
+            },
+            _ => match (self.as_real(), other.as_real()) {
+                (Some(a), Some(b)) => match (a.is_nan(), b.is_nan()) {
+                    (true, true) => Ordering::Equal,
+                    (true, false) => Ordering::Greater,
+                    (false, true) => Ordering::Less,
+                    (false, false) => a.partial_cmp(&b).unwrap(),
+                },
+                _ => self.rank().cmp(&other.rank())
+            }
+        }
+    }
+
+    pub fn as_text(&self) -> Option<String> {
+        match self {
+            DBValue::Integer(i) => Some(i.to_string()),
+            DBValue::Char(c) | DBValue::CharInvl(c) => Some(c.to_string()),
+            DBValue::Real(f) => Some(f.to_string()),
+            DBValue::Str(s) | DBValue::StrCI(s) => Some(s.clone()),
+            DBValue::Null => None,
         }
     }
 
     pub fn coerce(&self, t: &Type) -> Option<DBValue> {
-        if self.get_type().is_subtype(t) {
+        if self.get_type().map_or(true, |st| st.is_subtype(t)) {
             return Some(self.clone());
         }
         match (self, t) {
@@ -411,6 +1459,21 @@ impl Type {
         }
     }
 
+    pub fn unify(&self, other: &Type) -> Option<Type> {
+        match (self, other) {
+            _ if other.is_subtype(self) => Some(self.clone()),
+            _ if self.is_subtype(other) => Some(other.clone()),
+            (Type::CharInvl(a, b), Type::CharInvl(c, d)) => Some(Type::CharInvl(*a.min(c), *b.max(d))),
+            (Type::StrCI(a, b), Type::StrCI(c, d)) => Some(Type::StrCI(*a.min(c), *b.max(d))),
+            (Type::CharInvl(_, _), Type::Char) | (Type::Char, Type::CharInvl(_, _)) => Some(Type::Char),
+            (Type::Integer, Type::Real) | (Type::Real, Type::Integer) => Some(Type::Real),
+            (Type::Char, Type::Str) | (Type::Str, Type::Char) |
+                (Type::CharInvl(_, _), Type::Str) | (Type::Str, Type::CharInvl(_, _)) |
+                (Type::StrCI(_, _), Type::Str) | (Type::Str, Type::StrCI(_, _)) => Some(Type::Str),
+            (_, _) => None
+        }
+    }
+
     pub fn defvalue(&self) -> DBValue {
         match self {
             Type::Integer => DBValue::Integer(0),
@@ -422,3 +1485,56 @@ impl Type {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(db: &mut DB<MemStore>, ids: IdGen) {
+        let schema = Schema { columns: vec![Column::new("a", Type::Integer)], ids, ..Schema::default() };
+        db.add_table("t", &schema).unwrap();
+    }
+
+    fn add(db: &mut DB<MemStore>, i: i64) -> DBResult<u64> {
+        db.get_table("t").unwrap().add_record(&[DBValue::Integer(i)])
+    }
+
+    #[test]
+    fn sequence_ids() {
+        let mut db = DB::in_memory(MemStore::default());
+        table(&mut db, IdGen::Sequence);
+        let idents = (0..3).map(|i| add(&mut db, i).unwrap()).collect::<Vec<_>>();
+        assert_eq!(idents, vec![1, 2, 3]);
+        db.get_table("t").unwrap().del_record(3).unwrap();
+        assert_eq!(add(&mut db, 3).unwrap(), 4);
+
+        db.tree.set_value(&last_id_key("t"), &u64::max_value());
+        match add(&mut db, 4) {
+            Err(Overflow) => (),
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn time_ids_sort_in_insertion_order() {
+        let mut db = DB::in_memory(MemStore::default());
+        table(&mut db, IdGen::Time);
+        let idents = (0..50).map(|i| add(&mut db, i).unwrap()).collect::<Vec<_>>();
+        assert!(idents.windows(2).all(|w| w[0] < w[1]));
+
+        let records = db.get_table("t").unwrap().get_records();
+        let sorted = sort_by_keys(&db.get_table("t").unwrap().get_info().schema, records, &SortKey::parse_list("_id").unwrap(), Collation::Byte).unwrap();
+        let values: Vec<DBValue> = sorted.into_iter().map(|r| r.value[0].clone()).collect();
+        assert_eq!(values, (0..50).map(DBValue::Integer).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn id_column_is_reserved() {
+        let mut db = DB::in_memory(MemStore::default());
+        let schema = Schema { columns: vec![Column::new(ID_COLUMN, Type::Integer)], ..Schema::default() };
+        match db.add_table("t", &schema) {
+            Err(InvalidColumn) => (),
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::io::{self, Read};
+
+use serde_json::{Map, Value};
+
+use crate::changes::Subscriber;
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::getset::EasyGet;
+
+const CHUNK: usize = 500;
+const ERROR_FIELD: &str = "_error";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Jsonl,
+    Sql,
+}
+
+struct Cursor {
+    name: String,
+    schema: Schema,
+    idents: Vec<u64>,
+    next: usize,
+}
+
+/// Streams tables a chunk of rows at a time, taking the database lock for
+/// each chunk only. An export is therefore not a point-in-time snapshot: rows
+/// written while it runs may or may not be included, and rows deleted before
+/// their chunk is read are skipped. A table whose schema version changes
+/// between chunks fails the export. If the export fails part way, the stream
+/// ends with an error marker instead of the rest of the data: a `_error`
+/// object in JSON Lines, a `_error` row in CSV, and a comment followed by
+/// `ROLLBACK;` in SQL. In CSV, null is an empty field and an empty string is
+/// `""`.
+pub struct Export {
+    db: String,
+    format: Format,
+    whole: bool,
+    tables: Vec<String>,
+    current: Option<Cursor>,
+    footer: Vec<String>,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+    _sub: Subscriber,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> DBResult<Format> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            "sql" => Ok(Format::Sql),
+            _ => Err(InvalidQuery)
+        }
+    }
+
+    pub fn content_type(self) -> (&'static str, &'static str) {
+        match self {
+            Format::Csv => ("text", "csv"),
+            Format::Jsonl => ("application", "x-ndjson"),
+            Format::Sql => ("application", "sql"),
+        }
+    }
+}
+
+fn csv_field(value: &DBValue) -> String {
+    let text = match value.as_text() {
+        Some(text) => text,
+        None => return String::new()
+    };
+    if text.is_empty() || text.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn json_value(value: &DBValue) -> Value {
+    match value {
+        DBValue::Integer(i) => Value::from(*i),
+        DBValue::Real(f) => serde_json::Number::from_f64(*f).map_or(Value::Null, Value::Number),
+        DBValue::Null => Value::Null,
+        v => Value::String(v.as_text().unwrap_or_default()),
+    }
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn quote_text(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn sql_value(value: &DBValue) -> String {
+    match value {
+        DBValue::Integer(i) => i.to_string(),
+        DBValue::Real(f) if f.is_finite() => format!("{:?}", f),
+        DBValue::Real(_) | DBValue::Null => "NULL".to_string(),
+        v => quote_text(&v.as_text().unwrap_or_default()),
+    }
+}
+
+fn sql_type(column: &Column) -> String {
+    let name = quote_ident(&column.name);
+    let ty = match &column.ctype {
+        Type::Integer => "BIGINT".to_string(),
+        Type::Real => "DOUBLE PRECISION".to_string(),
+        Type::Char => "CHAR(1)".to_string(),
+        Type::CharInvl(a, b) => format!("CHAR(1) CHECK ({} BETWEEN {} AND {})", name, quote_text(&a.to_string()), quote_text(&b.to_string())),
+        Type::Str => "TEXT".to_string(),
+        Type::StrCI(a, b) => format!("TEXT /* characters {} to {} */", quote_text(&a.to_string()), quote_text(&b.to_string())),
+    };
+    let null = if column.nullable { "" } else { " NOT NULL" };
+    format!("{} {}{}", name, ty, null)
+}
+
+impl Export {
+    pub fn new(db: &str, tables: Vec<String>, format: Format, whole: bool) -> DBResult<Export> {
+        if format == Format::Csv && whole {
+            return Err(InvalidQuery);
+        }
+        let mut export = Export {
+            db: db.to_string(),
+            format,
+            whole,
+            tables: tables.into_iter().rev().collect(),
+            current: None,
+            footer: vec![],
+            buf: vec![],
+            pos: 0,
+            done: false,
+            _sub: Subscriber::new(db),
+        };
+        if format == Format::Sql {
+            export.buf.extend_from_slice(b"BEGIN;\n");
+        }
+        Ok(export)
+    }
+
+    fn header(&mut self, cursor: &Cursor) {
+        let columns: Vec<&str> = cursor.schema.columns.iter().map(|c| c.name.as_str()).collect();
+        match self.format {
+            Format::Csv => {
+                let header: Vec<String> = Some(ID_COLUMN).into_iter().chain(columns)
+                    .map(|c| csv_field(&DBValue::Str(c.to_string())))
+                    .collect();
+                self.buf.extend_from_slice(format!("{}\n", header.join(",")).as_bytes());
+            },
+            Format::Jsonl => (),
+            Format::Sql => {
+                let table = quote_ident(&cursor.name);
+                let defs: Vec<String> = Some(format!("{} BIGINT PRIMARY KEY", quote_ident(ID_COLUMN))).into_iter()
+                    .chain(cursor.schema.columns.iter().map(sql_type))
+                    .collect();
+                self.buf.extend_from_slice(format!("\nCREATE TABLE {} (\n    {}\n);\n", table, defs.join(",\n    ")).as_bytes());
+                for c in &cursor.schema.columns {
+                    if let Some(r) = &c.references {
+                        let target = r.column.as_ref().map_or(ID_COLUMN, String::as_str);
+                        self.footer.push(format!("ALTER TABLE {} ADD FOREIGN KEY ({}) REFERENCES {} ({});\n",
+                            table, quote_ident(&c.name), quote_ident(&r.table), quote_ident(target)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn row(&mut self, cursor: &Cursor, ident: u64, value: &[DBValue]) {
+        let line = match self.format {
+            Format::Csv => {
+                let fields: Vec<String> = Some(ident.to_string()).into_iter()
+                    .chain(value.iter().map(csv_field))
+                    .collect();
+                fields.join(",")
+            },
+            Format::Jsonl => {
+                let mut obj = Map::new();
+                if self.whole {
+                    obj.insert("_table".to_string(), Value::String(cursor.name.clone()));
+                }
+                obj.insert(ID_COLUMN.to_string(), Value::from(ident));
+                for (c, v) in cursor.schema.columns.iter().zip(value) {
+                    obj.insert(c.name.clone(), json_value(v));
+                }
+                Value::Object(obj).to_string()
+            },
+            Format::Sql => {
+                let values: Vec<String> = Some(ident.to_string()).into_iter()
+                    .chain(value.iter().map(sql_value))
+                    .collect();
+                format!("INSERT INTO {} VALUES ({});", quote_ident(&cursor.name), values.join(", "))
+            }
+        };
+        self.buf.extend_from_slice(line.as_bytes());
+        self.buf.push(b'\n');
+    }
+
+    fn fail(&mut self, e: &DBError) {
+        let message = serde_json::to_string(e).unwrap();
+        let line = match self.format {
+            Format::Csv => format!("{},{}\n", ERROR_FIELD, csv_field(&DBValue::Str(message))),
+            Format::Jsonl => format!("{{\"{}\":{}}}\n", ERROR_FIELD, message),
+            Format::Sql => format!("-- export failed: {}\nROLLBACK;\n", message),
+        };
+        self.buf.extend_from_slice(line.as_bytes());
+        self.done = true;
+    }
+
+    fn fill(&mut self) -> DBResult<()> {
+        self.buf.clear();
+        self.pos = 0;
+        let mut dbs = DATABASES.lock().unwrap();
+        let db = get_db(&mut *dbs, &self.db)?;
+        let mut cursor = match self.current.take() {
+            Some(cursor) => {
+                let schema: Schema = db.tree.get_value(&format!("#{}", cursor.name)).ok_or(TableNotFound)?;
+                if schema.version != cursor.schema.version {
+                    return Err(SchemaChanged);
+                }
+                cursor
+            },
+            None => match self.tables.pop() {
+                Some(name) => {
+                    let cursor = Cursor {
+                        schema: db.tree.get_value(&format!("#{}", name)).ok_or(TableNotFound)?,
+                        idents: db.tree.get_value(&format!("/{}", name)).ok_or(TableNotFound)?,
+                        name,
+                        next: 0,
+                    };
+                    self.header(&cursor);
+                    cursor
+                },
+                None => {
+                    if self.format == Format::Sql {
+                        for stmt in std::mem::replace(&mut self.footer, vec![]) {
+                            self.buf.extend_from_slice(stmt.as_bytes());
+                        }
+                        self.buf.extend_from_slice(b"COMMIT;\n");
+                    }
+                    self.done = true;
+                    return Ok(());
+                }
+            }
+        };
+        let end = (cursor.next + CHUNK).min(cursor.idents.len());
+        for i in cursor.next..end {
+            let ident = cursor.idents[i];
+            if let Some(value) = db.tree.get_value::<Vec<DBValue>>(&record_key(&cursor.name, ident)) {
+                self.row(&cursor, ident, &value);
+            }
+        }
+        cursor.next = end;
+        if cursor.next < cursor.idents.len() {
+            self.current = Some(cursor);
+        }
+        Ok(())
+    }
+}
+
+impl Read for Export {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            if let Err(e) = self.fill() {
+                self.fail(&e);
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_field(&DBValue::Null), "");
+        assert_eq!(csv_field(&DBValue::Str(String::new())), "\"\"");
+        assert_eq!(csv_field(&DBValue::Str("plain".to_string())), "plain");
+        assert_eq!(csv_field(&DBValue::Str("a,b".to_string())), "\"a,b\"");
+        assert_eq!(csv_field(&DBValue::Str("say \"hi\"".to_string())), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field(&DBValue::Integer(-3)), "-3");
+    }
+
+    #[test]
+    fn sql_values_and_types() {
+        assert_eq!(sql_value(&DBValue::CharInvl('c')), "'c'");
+        assert_eq!(sql_value(&DBValue::StrCI("it's".to_string())), "'it''s'");
+        assert_eq!(sql_value(&DBValue::Null), "NULL");
+        assert_eq!(sql_value(&DBValue::Real(std::f64::NAN)), "NULL");
+        assert_eq!(sql_value(&DBValue::Real(std::f64::NEG_INFINITY)), "NULL");
+        assert_eq!(sql_value(&DBValue::Real(1.0)), "1.0");
+
+        assert_eq!(sql_type(&Column::new("c", Type::CharInvl('a', 'z'))), "\"c\" CHAR(1) CHECK (\"c\" BETWEEN 'a' AND 'z') NOT NULL");
+        let column = Column { nullable: true, ..Column::new("s", Type::StrCI('a', 'f')) };
+        assert_eq!(sql_type(&column), "\"s\" TEXT /* characters 'a' to 'f' */");
+    }
+
+    #[test]
+    fn jsonl_rows_are_tagged_with_their_table() {
+        let cursor = Cursor {
+            name: "t".to_string(),
+            schema: Schema { columns: vec![Column::new("a", Type::Str)], ..Schema::default() },
+            idents: vec![4],
+            next: 0,
+        };
+        let mut whole = Export::new("db", vec![], Format::Jsonl, true).unwrap();
+        whole.row(&cursor, 4, &[DBValue::Str("x".to_string())]);
+        let line: Value = serde_json::from_slice(&whole.buf).unwrap();
+        assert_eq!(line, serde_json::json!({"_table": "t", "_id": 4, "a": "x"}));
+
+        let mut single = Export::new("db", vec![], Format::Jsonl, false).unwrap();
+        single.row(&cursor, 4, &[DBValue::Null]);
+        let line: Value = serde_json::from_slice(&single.buf).unwrap();
+        assert_eq!(line, serde_json::json!({"_id": 4, "a": null}));
+    }
+}
@@ -0,0 +1,706 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::db::*;
+use crate::db::DBError::*;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Expr {
+    Column(String),
+    Literal(DBValue),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Cast(Box<Expr>, Type),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Projection {
+    pub expr: Expr,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Real(f64),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+const OPS: [&str; 13] = ["||", "<=", ">=", "<>", "!=", "=", "<", ">", "+", "-", "*", "/", "%"];
+
+fn tokenize(s: &str) -> DBResult<Vec<Token>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                i += 1;
+                if i < chars.len() && (chars[i] == '+' || chars[i] == '-') {
+                    i += 1;
+                }
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            if text.contains(|x: char| x == '.' || x == 'e' || x == 'E') {
+                tokens.push(Token::Real(text.parse().map_err(|_| InvalidExpression)?));
+            } else {
+                tokens.push(Token::Int(text.parse().map_err(|_| InvalidExpression)?));
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '\'' || c == '"' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(InvalidExpression),
+                    Some(q) if *q == c => {
+                        if chars.get(i + 1) == Some(&c) {
+                            text.push(c);
+                            i += 2;
+                        } else {
+                            i += 1;
+                            break;
+                        }
+                    },
+                    Some(x) => {
+                        text.push(*x);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(if c == '\'' { Token::Str(text) } else { Token::Ident(text) });
+        } else if c == '(' || c == ')' || c == ',' {
+            tokens.push(match c {
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                _ => Token::Comma
+            });
+            i += 1;
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            let op = OPS.iter().find(|op| rest.starts_with(*op)).ok_or(InvalidExpression)?;
+            tokens.push(Token::Op(*op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(s: &str) -> DBResult<Parser> {
+        Ok(Parser {
+            tokens: tokenize(s)?,
+            pos: 0
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn eat(&mut self, t: &Token) -> bool {
+        if self.peek() == Some(t) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, t: &Token) -> DBResult<()> {
+        if self.eat(t) { Ok(()) } else { Err(InvalidExpression) }
+    }
+
+    fn end(&self) -> DBResult<()> {
+        if self.pos == self.tokens.len() { Ok(()) } else { Err(InvalidExpression) }
+    }
+
+    fn keyword(&mut self, kw: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(s)) if s.eq_ignore_ascii_case(kw) => {
+                self.pos += 1;
+                true
+            },
+            _ => false
+        }
+    }
+
+    fn op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(o)) if ops.contains(o) => {
+                let o = *o;
+                self.pos += 1;
+                Some(o)
+            },
+            _ => None
+        }
+    }
+
+    fn ident(&mut self) -> DBResult<String> {
+        match self.next() {
+            Some(Token::Ident(s)) => Ok(s),
+            _ => Err(InvalidExpression)
+        }
+    }
+
+    fn expr(&mut self) -> DBResult<Expr> {
+        let mut l = self.and()?;
+        while self.keyword("or") {
+            l = Expr::Binary(BinOp::Or, Box::new(l), Box::new(self.and()?));
+        }
+        Ok(l)
+    }
+
+    fn and(&mut self) -> DBResult<Expr> {
+        let mut l = self.not()?;
+        while self.keyword("and") {
+            l = Expr::Binary(BinOp::And, Box::new(l), Box::new(self.not()?));
+        }
+        Ok(l)
+    }
+
+    fn not(&mut self) -> DBResult<Expr> {
+        if self.keyword("not") {
+            Ok(Expr::Unary(UnOp::Not, Box::new(self.not()?)))
+        } else {
+            self.cmp()
+        }
+    }
+
+    fn cmp(&mut self) -> DBResult<Expr> {
+        let l = self.concat()?;
+        let op = match self.op(&["=", "<>", "!=", "<=", ">=", "<", ">"]) {
+            Some("=") => BinOp::Eq,
+            Some("<>") | Some("!=") => BinOp::Ne,
+            Some("<=") => BinOp::Le,
+            Some(">=") => BinOp::Ge,
+            Some("<") => BinOp::Lt,
+            Some(_) => BinOp::Gt,
+            None => return Ok(l)
+        };
+        Ok(Expr::Binary(op, Box::new(l), Box::new(self.concat()?)))
+    }
+
+    fn concat(&mut self) -> DBResult<Expr> {
+        let mut l = self.additive()?;
+        while self.op(&["||"]).is_some() {
+            l = Expr::Binary(BinOp::Concat, Box::new(l), Box::new(self.additive()?));
+        }
+        Ok(l)
+    }
+
+    fn additive(&mut self) -> DBResult<Expr> {
+        let mut l = self.multiplicative()?;
+        while let Some(o) = self.op(&["+", "-"]) {
+            let op = if o == "+" { BinOp::Add } else { BinOp::Sub };
+            l = Expr::Binary(op, Box::new(l), Box::new(self.multiplicative()?));
+        }
+        Ok(l)
+    }
+
+    fn multiplicative(&mut self) -> DBResult<Expr> {
+        let mut l = self.unary()?;
+        while let Some(o) = self.op(&["*", "/", "%"]) {
+            let op = match o {
+                "*" => BinOp::Mul,
+                "/" => BinOp::Div,
+                _ => BinOp::Mod
+            };
+            l = Expr::Binary(op, Box::new(l), Box::new(self.unary()?));
+        }
+        Ok(l)
+    }
+
+    fn unary(&mut self) -> DBResult<Expr> {
+        if self.op(&["-"]).is_some() {
+            Ok(Expr::Unary(UnOp::Neg, Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> DBResult<Expr> {
+        match self.next() {
+            Some(Token::Int(i)) => Ok(Expr::Literal(DBValue::Integer(i))),
+            Some(Token::Real(f)) => Ok(Expr::Literal(DBValue::Real(f))),
+            Some(Token::Str(s)) => Ok(Expr::Literal(DBValue::Str(s))),
+            Some(Token::LParen) => {
+                let e = self.expr()?;
+                self.expect(&Token::RParen)?;
+                Ok(e)
+            },
+            Some(Token::Ident(ref s)) if s.eq_ignore_ascii_case("null") => Ok(Expr::Literal(DBValue::Null)),
+            Some(Token::Ident(ref s)) if s.eq_ignore_ascii_case("cast") && self.eat(&Token::LParen) => {
+                let e = self.expr()?;
+                if !self.keyword("as") {
+                    return Err(InvalidExpression);
+                }
+                let t = self.ctype()?;
+                self.expect(&Token::RParen)?;
+                Ok(Expr::Cast(Box::new(e), t))
+            },
+            Some(Token::Ident(s)) => {
+                if !self.eat(&Token::LParen) {
+                    return Ok(Expr::Column(s));
+                }
+                let mut name = s.to_lowercase();
+                let mut args = vec![];
+                if self.keyword("distinct") {
+                    name.push_str("_distinct");
+                }
+                if self.op(&["*"]).is_some() {
+                    self.expect(&Token::RParen)?;
+                } else if !self.eat(&Token::RParen) {
+                    loop {
+                        args.push(self.expr()?);
+                        if !self.eat(&Token::Comma) {
+                            break;
+                        }
+                    }
+                    self.expect(&Token::RParen)?;
+                }
+                Ok(Expr::Call(name, args))
+            },
+            _ => Err(InvalidExpression)
+        }
+    }
+
+    fn bound(&mut self) -> DBResult<char> {
+        match self.next() {
+            Some(Token::Str(ref s)) if s.chars().count() == 1 => Ok(s.chars().next().unwrap()),
+            _ => Err(InvalidExpression)
+        }
+    }
+
+    fn ctype(&mut self) -> DBResult<Type> {
+        let name = self.ident()?.to_lowercase();
+        match name.as_str() {
+            "integer" => Ok(Type::Integer),
+            "real" => Ok(Type::Real),
+            "char" => Ok(Type::Char),
+            "str" => Ok(Type::Str),
+            "charinvl" | "strci" => {
+                self.expect(&Token::LParen)?;
+                let from = self.bound()?;
+                self.expect(&Token::Comma)?;
+                let to = self.bound()?;
+                self.expect(&Token::RParen)?;
+                Ok(if name == "strci" { Type::StrCI(from, to) } else { Type::CharInvl(from, to) })
+            },
+            _ => Err(InvalidExpression)
+        }
+    }
+}
+
+pub fn parse_projection(s: &str) -> DBResult<Vec<Projection>> {
+    let mut p = Parser::new(s)?;
+    let mut res = vec![];
+    loop {
+        let expr = p.expr()?;
+        let alias = if p.keyword("as") { Some(p.ident()?) } else { None };
+        res.push(Projection { expr, alias });
+        if !p.eat(&Token::Comma) {
+            break;
+        }
+    }
+    p.end()?;
+    Ok(res)
+}
+
+impl Projection {
+    pub fn name(&self) -> String {
+        match (&self.alias, &self.expr) {
+            (Some(a), _) => a.clone(),
+            (None, Expr::Column(c)) => c.clone(),
+            (None, e) => e.to_string()
+        }
+    }
+}
+
+fn boolean(b: bool) -> DBValue {
+    DBValue::Integer(b as i64)
+}
+
+fn truth(v: &DBValue) -> DBResult<Option<bool>> {
+    match v {
+        DBValue::Null => Ok(None),
+        DBValue::Integer(i) => Ok(Some(*i != 0)),
+        DBValue::Real(f) => Ok(Some(*f != 0.0)),
+        _ => Err(TypeMismatch)
+    }
+}
+
+pub fn is_true(v: &DBValue) -> bool {
+    truth(v).ok() == Some(Some(true))
+}
+
+fn number(v: &DBValue) -> Option<f64> {
+    match v {
+        DBValue::Integer(i) => Some(*i as f64),
+        DBValue::Real(f) => Some(*f),
+        _ => None
+    }
+}
+
+fn compare(a: &DBValue, b: &DBValue) -> DBResult<Option<Ordering>> {
+    match (a, b) {
+        (DBValue::Integer(x), DBValue::Integer(y)) => Ok(Some(x.cmp(y))),
+        (DBValue::StrCI(_), _) | (_, DBValue::StrCI(_)) => {
+            let (x, y) = (a.as_text().ok_or(TypeMismatch)?, b.as_text().ok_or(TypeMismatch)?);
+            Ok(Some(x.to_lowercase().cmp(&y.to_lowercase())))
+        },
+        _ => match (number(a), number(b)) {
+            (Some(x), Some(y)) => Ok(x.partial_cmp(&y)),
+            (None, None) => Ok(Some(a.as_text().ok_or(TypeMismatch)?.cmp(&b.as_text().ok_or(TypeMismatch)?))),
+            _ => Err(TypeMismatch)
+        }
+    }
+}
+
+fn arith(op: BinOp, a: &DBValue, b: &DBValue) -> DBResult<DBValue> {
+    if let (DBValue::Integer(x), DBValue::Integer(y)) = (a, b) {
+        if *y == 0 && (op == BinOp::Div || op == BinOp::Mod) {
+            return Err(DivisionByZero);
+        }
+        let res = match op {
+            BinOp::Add => x.checked_add(*y),
+            BinOp::Sub => x.checked_sub(*y),
+            BinOp::Mul => x.checked_mul(*y),
+            BinOp::Div => x.checked_div(*y),
+            _ => x.checked_rem(*y)
+        };
+        return res.map(DBValue::Integer).ok_or(Overflow);
+    }
+    let (x, y) = (number(a).ok_or(TypeMismatch)?, number(b).ok_or(TypeMismatch)?);
+    Ok(DBValue::Real(match op {
+        BinOp::Add => x + y,
+        BinOp::Sub => x - y,
+        BinOp::Mul => x * y,
+        BinOp::Div => x / y,
+        _ => x % y
+    }))
+}
+
+fn binary(op: BinOp, a: DBValue, b: DBValue) -> DBResult<DBValue> {
+    match op {
+        BinOp::And | BinOp::Or => {
+            let (x, y) = (truth(&a)?, truth(&b)?);
+            let dominant = op == BinOp::Or;
+            Ok(match (x, y) {
+                (Some(v), _) | (_, Some(v)) if v == dominant => boolean(dominant),
+                (Some(_), Some(_)) => boolean(!dominant),
+                _ => DBValue::Null
+            })
+        },
+        _ if a == DBValue::Null || b == DBValue::Null => Ok(DBValue::Null),
+        BinOp::Concat => Ok(DBValue::Str(a.as_text().ok_or(TypeMismatch)? + &b.as_text().ok_or(TypeMismatch)?)),
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            let ord = match compare(&a, &b)? {
+                Some(ord) => ord,
+                None => return Ok(boolean(op == BinOp::Ne))
+            };
+            Ok(boolean(match op {
+                BinOp::Eq => ord == Ordering::Equal,
+                BinOp::Ne => ord != Ordering::Equal,
+                BinOp::Lt => ord == Ordering::Less,
+                BinOp::Le => ord != Ordering::Greater,
+                BinOp::Gt => ord == Ordering::Greater,
+                _ => ord != Ordering::Less
+            }))
+        },
+        _ => arith(op, &a, &b)
+    }
+}
+
+fn unary(op: UnOp, v: DBValue) -> DBResult<DBValue> {
+    match (op, v) {
+        (_, DBValue::Null) => Ok(DBValue::Null),
+        (UnOp::Neg, DBValue::Integer(i)) => i.checked_neg().map(DBValue::Integer).ok_or(Overflow),
+        (UnOp::Neg, DBValue::Real(f)) => Ok(DBValue::Real(-f)),
+        (UnOp::Neg, _) => Err(TypeMismatch),
+        (UnOp::Not, v) => Ok(truth(&v)?.map_or(DBValue::Null, |b| boolean(!b))),
+    }
+}
+
+fn call(f: &str, args: Vec<DBValue>) -> DBResult<DBValue> {
+    match (f, args.as_slice()) {
+        ("coalesce", _) => Ok(args.iter().find(|a| **a != DBValue::Null).cloned().unwrap_or(DBValue::Null)),
+        (_, [DBValue::Null]) => Ok(DBValue::Null),
+        ("upper", [DBValue::StrCI(s)]) => Ok(DBValue::StrCI(s.to_uppercase())),
+        ("lower", [DBValue::StrCI(s)]) => Ok(DBValue::StrCI(s.to_lowercase())),
+        ("upper", [v]) => Ok(DBValue::Str(v.as_text().ok_or(TypeMismatch)?.to_uppercase())),
+        ("lower", [v]) => Ok(DBValue::Str(v.as_text().ok_or(TypeMismatch)?.to_lowercase())),
+        ("length", [v]) => Ok(DBValue::Integer(v.as_text().ok_or(TypeMismatch)?.chars().count() as i64)),
+        ("abs", [DBValue::Integer(i)]) => i.checked_abs().map(DBValue::Integer).ok_or(Overflow),
+        ("abs", [DBValue::Real(f)]) => Ok(DBValue::Real(f.abs())),
+        ("concat", _) => {
+            let mut s = String::new();
+            for a in &args {
+                if let Some(t) = a.as_text() {
+                    s.push_str(&t);
+                }
+            }
+            Ok(DBValue::Str(s))
+        },
+        _ => Err(InvalidExpression)
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> DBResult<Expr> {
+        let mut p = Parser::new(s)?;
+        let e = p.expr()?;
+        p.end()?;
+        Ok(e)
+    }
+
+    pub fn infer_type(&self, columns: &[Column]) -> Type {
+        match self {
+            Expr::Column(c) => columns.iter().find(|x| x.name == *c).map_or(Type::Str, |x| x.ctype.clone()),
+            Expr::Literal(v) => v.get_type().unwrap_or(Type::Str),
+            Expr::Unary(UnOp::Neg, e) => e.infer_type(columns),
+            Expr::Unary(UnOp::Not, _) => Type::Integer,
+            Expr::Binary(BinOp::Concat, _, _) => Type::Str,
+            Expr::Binary(BinOp::Add, l, r) | Expr::Binary(BinOp::Sub, l, r) | Expr::Binary(BinOp::Mul, l, r) |
+                Expr::Binary(BinOp::Div, l, r) | Expr::Binary(BinOp::Mod, l, r) =>
+                match (l.infer_type(columns), r.infer_type(columns)) {
+                    (Type::Integer, Type::Integer) => Type::Integer,
+                    _ => Type::Real
+                },
+            Expr::Binary(_, _, _) => Type::Integer,
+            Expr::Call(f, args) => match (f.as_str(), args.first()) {
+                ("length", _) | ("count", _) | ("count_distinct", _) => Type::Integer,
+                ("abs", Some(a)) | ("coalesce", Some(a)) => a.infer_type(columns),
+                ("upper", Some(a)) | ("lower", Some(a)) => match a.infer_type(columns) {
+                    Type::StrCI(_, _) => Type::StrCI('\0', std::char::MAX),
+                    _ => Type::Str
+                },
+                _ => Type::Str
+            },
+            Expr::Cast(_, t) => t.clone(),
+        }
+    }
+
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            Expr::Column(c) => vec![c.as_str()],
+            Expr::Literal(_) => vec![],
+            Expr::Unary(_, e) | Expr::Cast(e, _) => e.columns(),
+            Expr::Binary(_, l, r) => {
+                let mut res = l.columns();
+                res.extend(r.columns());
+                res
+            },
+            Expr::Call(_, args) => args.iter().flat_map(|a| a.columns()).collect(),
+        }
+    }
+
+    pub fn eval(&self, columns: &[String], row: &[DBValue]) -> DBResult<DBValue> {
+        match self {
+            Expr::Column(c) => {
+                let idx = columns.iter().position(|x| x == c).ok_or(InvalidColumn)?;
+                Ok(row[idx].clone())
+            },
+            Expr::Literal(v) => Ok(v.clone()),
+            Expr::Unary(op, e) => unary(*op, e.eval(columns, row)?),
+            Expr::Binary(op, l, r) => binary(*op, l.eval(columns, row)?, r.eval(columns, row)?),
+            Expr::Call(f, args) => {
+                let args = args.iter()
+                    .map(|a| a.eval(columns, row))
+                    .collect::<DBResult<Vec<_>>>()?;
+                call(f, args)
+            },
+            Expr::Cast(e, t) => e.eval(columns, row)?.coerce(t).ok_or(TypeMismatch),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Column(c) if c.chars().all(|x| x.is_alphanumeric() || x == '_' || x == '.') => write!(f, "{}", c),
+            Expr::Column(c) => write!(f, "\"{}\"", c.replace('"', "\"\"")),
+            Expr::Literal(DBValue::Integer(i)) => write!(f, "{}", i),
+            Expr::Literal(DBValue::Real(r)) => write!(f, "{:?}", r),
+            Expr::Literal(DBValue::Null) => write!(f, "NULL"),
+            Expr::Literal(v) => write!(f, "'{}'", v.as_text().unwrap_or_default().replace('\'', "''")),
+            Expr::Unary(UnOp::Neg, e) => write!(f, "-{}", e),
+            Expr::Unary(UnOp::Not, e) => write!(f, "NOT {}", e),
+            Expr::Binary(op, l, r) => {
+                let op = match op {
+                    BinOp::Add => "+",
+                    BinOp::Sub => "-",
+                    BinOp::Mul => "*",
+                    BinOp::Div => "/",
+                    BinOp::Mod => "%",
+                    BinOp::Concat => "||",
+                    BinOp::Eq => "=",
+                    BinOp::Ne => "<>",
+                    BinOp::Lt => "<",
+                    BinOp::Le => "<=",
+                    BinOp::Gt => ">",
+                    BinOp::Ge => ">=",
+                    BinOp::And => "AND",
+                    BinOp::Or => "OR",
+                };
+                write!(f, "({} {} {})", l, op, r)
+            },
+            Expr::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, a) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", a)?;
+                }
+                write!(f, ")")
+            },
+            Expr::Cast(e, t) => write!(f, "cast({} as {:?})", e, t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> DBResult<DBValue> {
+        let columns = vec!["a".to_string(), "b".to_string(), "t.name".to_string(), "n".to_string()];
+        let row = vec![DBValue::Integer(7), DBValue::Real(2.5), DBValue::Str("Ann".to_string()), DBValue::Null];
+        Expr::parse(s)?.eval(&columns, &row)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), DBValue::Integer(7));
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), DBValue::Integer(9));
+        assert_eq!(eval("-a + 1").unwrap(), DBValue::Integer(-6));
+        assert_eq!(eval("a > 5 and b < 3 or 0").unwrap(), DBValue::Integer(1));
+        assert_eq!(eval("not a = 7").unwrap(), DBValue::Integer(0));
+    }
+
+    #[test]
+    fn columns_and_literals() {
+        assert_eq!(eval("a * b").unwrap(), DBValue::Real(17.5));
+        assert_eq!(eval("t.name || '!'").unwrap(), DBValue::Str("Ann!".to_string()));
+        assert_eq!(eval("\"t.name\" = 'Ann'").unwrap(), DBValue::Integer(1));
+        assert_eq!(eval("'it''s'").unwrap(), DBValue::Str("it's".to_string()));
+        assert_eq!(eval("1e2").unwrap(), DBValue::Real(100.0));
+    }
+
+    #[test]
+    fn nulls() {
+        assert_eq!(eval("n + 1").unwrap(), DBValue::Null);
+        assert_eq!(eval("n = n").unwrap(), DBValue::Null);
+        assert_eq!(eval("n or 1").unwrap(), DBValue::Integer(1));
+        assert_eq!(eval("n and 0").unwrap(), DBValue::Integer(0));
+        assert_eq!(eval("coalesce(n, a)").unwrap(), DBValue::Integer(7));
+    }
+
+    #[test]
+    fn functions_and_casts() {
+        assert_eq!(eval("upper(t.name)").unwrap(), DBValue::Str("ANN".to_string()));
+        assert_eq!(eval("length(t.name)").unwrap(), DBValue::Integer(3));
+        assert_eq!(eval("abs(-a)").unwrap(), DBValue::Integer(7));
+        assert_eq!(eval("cast(a as real)").unwrap(), DBValue::Real(7.0));
+    }
+
+    #[test]
+    fn errors() {
+        match eval("a / 0") {
+            Err(DivisionByZero) => (),
+            r => panic!("{:?}", r)
+        }
+        match eval("9223372036854775807 + a") {
+            Err(Overflow) => (),
+            r => panic!("{:?}", r)
+        }
+        match eval("missing + 1") {
+            Err(InvalidColumn) => (),
+            r => panic!("{:?}", r)
+        }
+        match Expr::parse("a +") {
+            Err(InvalidExpression) => (),
+            r => panic!("{:?}", r)
+        }
+        match Expr::parse("'open") {
+            Err(InvalidExpression) => (),
+            r => panic!("{:?}", r)
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in &["a + b * 2", "upper(t.name) || 'x'", "not (a >= 3 and n <> 1)", "count(distinct a)"] {
+            let e = Expr::parse(s).unwrap();
+            assert_eq!(Expr::parse(&e.to_string()).unwrap(), e);
+        }
+    }
+
+    #[test]
+    fn projections() {
+        let p = parse_projection("a, b * 2 as double, upper(t.name)").unwrap();
+        assert_eq!(p.len(), 3);
+        assert_eq!(p[0].name(), "a");
+        assert_eq!(p[1].name(), "double");
+        assert_eq!(p[2].name(), "upper(t.name)");
+    }
+}
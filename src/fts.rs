@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::db::*;
+use crate::getset::{EasyGet, GetSet};
+
+type Postings = BTreeMap<u64, Vec<u32>>;
+
+enum Clause {
+    Term(String),
+    Prefix(String),
+    Phrase(Vec<String>),
+}
+
+pub fn tokenize(s: &str) -> Vec<String> {
+    fold(s)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn term_key(table: &str, column: &str, term: &str) -> String {
+    format!("%{}/{}/{}", table, column, term)
+}
+
+fn column_prefix(table: &str, column: &str) -> String {
+    format!("%{}/{}/", table, column)
+}
+
+pub fn table_prefix(table: &str) -> String {
+    format!("%{}/", table)
+}
+
+fn positions(value: &DBValue) -> BTreeMap<String, Vec<u32>> {
+    let mut res: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    if let Some(text) = value.as_text() {
+        for (pos, term) in tokenize(&text).into_iter().enumerate() {
+            res.entry(term).or_insert_with(Vec::new).push(pos as u32);
+        }
+    }
+    res
+}
+
+pub fn index_value<KV: GetSet>(db: &KV, table: &str, column: &str, ident: u64, value: &DBValue, add: bool) {
+    for (term, pos) in positions(value) {
+        let k = term_key(table, column, &term);
+        let mut postings: Postings = db.get_value(&k).unwrap_or_default();
+        if add {
+            postings.insert(ident, pos);
+        } else {
+            postings.remove(&ident);
+        }
+        if postings.is_empty() {
+            db.del(&k);
+        } else {
+            db.set_value(&k, &postings);
+        }
+    }
+}
+
+pub fn index_row<KV: GetSet>(db: &KV, table: &str, schema: &Schema, ident: u64, value: &[DBValue], add: bool) {
+    for (c, v) in schema.columns.iter().zip(value) {
+        if c.fulltext {
+            index_value(db, table, &c.name, ident, v, add);
+        }
+    }
+}
+
+pub fn drop_column<KV: GetSet>(db: &KV, table: &str, column: &str) {
+    for k in db.keys(&column_prefix(table, column)) {
+        db.del(&k);
+    }
+}
+
+pub fn drop_table<KV: GetSet>(db: &KV, table: &str) {
+    for k in db.keys(&table_prefix(table)) {
+        db.del(&k);
+    }
+}
+
+pub fn rename_table<KV: GetSet>(db: &KV, old: &str, new: &str) {
+    let prefix = table_prefix(old);
+    for k in db.keys(&prefix) {
+        db.set_unsafe(&format!("{}{}", table_prefix(new), &k[prefix.len()..]), db.get_unsafe(&k));
+        db.del(&k);
+    }
+}
+
+fn parse_query(q: &str) -> Vec<Clause> {
+    let mut clauses = vec![];
+    for (i, part) in q.split('"').enumerate() {
+        if i % 2 == 1 {
+            let words = tokenize(part);
+            if !words.is_empty() {
+                clauses.push(Clause::Phrase(words));
+            }
+            continue;
+        }
+        for word in part.split_whitespace() {
+            let prefix = word.ends_with('*');
+            for term in tokenize(word) {
+                clauses.push(if prefix { Clause::Prefix(term) } else { Clause::Term(term) });
+            }
+        }
+    }
+    clauses
+}
+
+fn clause_hits<KV: GetSet>(db: &KV, table: &str, column: &str, clause: &Clause, hits: &mut HashMap<u64, f64>) {
+    match clause {
+        Clause::Term(t) => {
+            let postings: Postings = db.get_value(&term_key(table, column, t)).unwrap_or_default();
+            for (ident, pos) in postings {
+                *hits.entry(ident).or_insert(0.0) += pos.len() as f64;
+            }
+        },
+        Clause::Prefix(p) => {
+            for k in db.keys(&term_key(table, column, p)) {
+                let postings: Postings = db.get_value(&k).unwrap_or_default();
+                for (ident, pos) in postings {
+                    *hits.entry(ident).or_insert(0.0) += pos.len() as f64;
+                }
+            }
+        },
+        Clause::Phrase(words) => {
+            let lists: Vec<Postings> = words.iter()
+                .map(|w| db.get_value(&term_key(table, column, w)).unwrap_or_default())
+                .collect();
+            for (ident, starts) in &lists[0] {
+                let count = starts.iter()
+                    .filter(|&&p| lists.iter().enumerate().skip(1).all(|(i, l)| {
+                        l.get(ident).map_or(false, |pos| pos.contains(&(p + i as u32)))
+                    }))
+                    .count();
+                if count > 0 {
+                    *hits.entry(*ident).or_insert(0.0) += count as f64;
+                }
+            }
+        }
+    }
+}
+
+pub fn search<KV: GetSet>(db: &KV, table: &str, schema: &Schema, total: usize, q: &str) -> Vec<(u64, f64)> {
+    let columns: Vec<&Column> = schema.columns.iter().filter(|c| c.fulltext).collect();
+    let mut scores: Option<HashMap<u64, f64>> = None;
+    for clause in parse_query(q) {
+        let mut hits = HashMap::new();
+        for c in &columns {
+            clause_hits(db, table, &c.name, &clause, &mut hits);
+        }
+        let idf = (1.0 + total as f64 / (1.0 + hits.len() as f64)).ln();
+        scores = Some(match scores {
+            None => hits.into_iter().map(|(i, tf)| (i, tf * idf)).collect(),
+            Some(prev) => prev.into_iter()
+                .filter_map(|(i, s)| hits.get(&i).map(|tf| (i, s + tf * idf)))
+                .collect()
+        });
+    }
+    let mut res: Vec<(u64, f64)> = scores.unwrap_or_default().into_iter().collect();
+    res.sort_by(|a, b| DBValue::Real(b.1).total_cmp(&DBValue::Real(a.1), Collation::Byte).then_with(|| a.0.cmp(&b.0)));
+    res
+}
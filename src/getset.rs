@@ -1,3 +1,6 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
 use bincode::{serialize, deserialize};
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
@@ -8,6 +11,9 @@ pub trait GetSet {
     fn get_unsafe(&self, k: &str) -> Vec<u8>;
     fn del(&self, k: &str) -> bool;
     fn has_key(&self, k: &str) -> bool;
+    fn keys(&self, prefix: &str) -> Vec<String>;
+    /// Up to `limit` keys under `prefix`, in order, starting at `start`.
+    fn scan_keys(&self, prefix: &str, start: &str, limit: usize) -> Vec<String>;
 }
 
 pub trait EasyGet {
@@ -31,6 +37,159 @@ impl GetSet for Tree {
     fn has_key(&self, k: &str) -> bool {
         self.get(k.as_bytes()).unwrap().is_some()
     }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        self.scan(prefix.as_bytes())
+            .filter_map(|r| r.ok())
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(prefix.as_bytes()))
+            .filter_map(|k| String::from_utf8(k).ok())
+            .collect()
+    }
+
+    fn scan_keys(&self, prefix: &str, start: &str, limit: usize) -> Vec<String> {
+        self.scan(start.as_bytes())
+            .filter_map(|r| r.ok())
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(prefix.as_bytes()))
+            .take(limit)
+            .filter_map(|k| String::from_utf8(k).ok())
+            .collect()
+    }
+}
+
+pub struct Txn<'a, KV: GetSet> {
+    base: &'a KV,
+    writes: RefCell<BTreeMap<String, Option<Vec<u8>>>>,
+}
+
+impl<'a, KV> Txn<'a, KV>
+    where KV: GetSet {
+    pub fn new(base: &'a KV) -> Txn<'a, KV> {
+        Txn {
+            base,
+            writes: RefCell::new(BTreeMap::new())
+        }
+    }
+
+    pub fn commit(self) {
+        for (k, v) in self.writes.into_inner() {
+            match v {
+                Some(v) => self.base.set_unsafe(&k, v),
+                None => { self.base.del(&k); }
+            }
+        }
+    }
+}
+
+impl<'a, KV> GetSet for Txn<'a, KV>
+    where KV: GetSet {
+    fn set_unsafe(&self, k: &str, v: Vec<u8>) {
+        self.writes.borrow_mut().insert(k.to_string(), Some(v));
+    }
+
+    fn get_unsafe(&self, k: &str) -> Vec<u8> {
+        match self.writes.borrow().get(k) {
+            Some(v) => v.clone().unwrap(),
+            None => self.base.get_unsafe(k)
+        }
+    }
+
+    fn del(&self, k: &str) -> bool {
+        self.writes.borrow_mut().insert(k.to_string(), None);
+        true
+    }
+
+    fn has_key(&self, k: &str) -> bool {
+        match self.writes.borrow().get(k) {
+            Some(v) => v.is_some(),
+            None => self.base.has_key(k)
+        }
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        let mut keys: BTreeSet<String> = self.base.keys(prefix).into_iter().collect();
+        for (k, v) in self.writes.borrow().range(prefix.to_string()..) {
+            if !k.starts_with(prefix) {
+                break;
+            }
+            if v.is_some() {
+                keys.insert(k.clone());
+            } else {
+                keys.remove(k);
+            }
+        }
+        keys.into_iter().collect()
+    }
+
+    fn scan_keys(&self, prefix: &str, start: &str, limit: usize) -> Vec<String> {
+        let writes = self.writes.borrow();
+        let pending = writes.range(start.to_string()..).take_while(|(k, _)| k.starts_with(prefix));
+        // Fetch enough base keys to make up for the ones deleted in this transaction.
+        let hidden = pending.clone().filter(|(_, v)| v.is_none()).count();
+        let mut keys: BTreeSet<String> = self.base.scan_keys(prefix, start, limit.saturating_add(hidden)).into_iter().collect();
+        for (k, v) in pending {
+            if v.is_some() {
+                keys.insert(k.clone());
+            } else {
+                keys.remove(k);
+            }
+        }
+        keys.into_iter().take(limit).collect()
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemStore {
+    data: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl GetSet for MemStore {
+    fn set_unsafe(&self, k: &str, v: Vec<u8>) {
+        self.data.borrow_mut().insert(k.to_string(), v);
+    }
+
+    fn get_unsafe(&self, k: &str) -> Vec<u8> {
+        self.data.borrow()[k].clone()
+    }
+
+    fn del(&self, k: &str) -> bool {
+        self.data.borrow_mut().remove(k);
+        true
+    }
+
+    fn has_key(&self, k: &str) -> bool {
+        self.data.borrow().contains_key(k)
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        self.data.borrow().range(prefix.to_string()..)
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    fn scan_keys(&self, prefix: &str, start: &str, limit: usize) -> Vec<String> {
+        self.data.borrow().range(start.to_string()..)
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn decode_exact<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    let mut rest = bytes;
+    let value = bincode::deserialize_from(&mut rest).ok()?;
+    if rest.is_empty() {
+        Some(value)
+    } else {
+        None
+    }
 }
 
 impl<TStore> EasyGet for TStore
@@ -0,0 +1,331 @@
+use std::collections::BTreeSet;
+
+use bincode::deserialize;
+use serde::de::DeserializeOwned;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::catalog;
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::fts;
+use crate::getset::{EasyGet, GetSet, decode_exact};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Issue {
+    MissingRecord { table: String, ident: u64 },
+    SchemaMismatch { table: String, ident: u64 },
+    MissingTable { table: String },
+    UnlistedTable { table: String },
+    DuplicateRecord { table: String, ident: u64 },
+    CorruptSchema { table: String },
+    CorruptList { table: String },
+    CorruptTableList,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RepairMode {
+    Drop,
+    Quarantine,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Quarantined {
+    pub table: String,
+    pub ident: u64,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CheckReport {
+    pub issues: Vec<Issue>,
+    pub repaired: usize,
+}
+
+impl RepairMode {
+    pub fn parse(s: &str) -> DBResult<RepairMode> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(RepairMode::Drop),
+            "quarantine" => Ok(RepairMode::Quarantine),
+            _ => Err(InvalidQuery)
+        }
+    }
+}
+
+fn quarantine_prefix(table: &str) -> String {
+    format!("*{}/", table)
+}
+
+fn quarantine_key(table: &str, ident: u64) -> String {
+    format!("{}{}", quarantine_prefix(table), ident)
+}
+
+pub fn forget<KV: GetSet>(db: &KV, table: &str) {
+    for k in db.keys(&quarantine_prefix(table)) {
+        db.del(&k);
+    }
+}
+
+pub fn rename<KV: GetSet>(db: &KV, old: &str, new: &str) {
+    let prefix = quarantine_prefix(old);
+    for k in db.keys(&prefix) {
+        let to = format!("{}{}", quarantine_prefix(new), &k[prefix.len()..]);
+        match db.get_value::<Quarantined>(&k) {
+            Some(q) => db.set_value(&to, &Quarantined { table: new.to_string(), ..q }),
+            None => db.set_unsafe(&to, db.get_unsafe(&k))
+        }
+        db.del(&k);
+    }
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    fn row_matches(&self, table: &str, schema: &Schema, ident: u64) -> bool {
+        deserialize::<Vec<DBValue>>(&self.tree.get_unsafe(&record_key(table, ident)))
+            .map_or(false, |v| schema.match_record(&v))
+    }
+
+    // Ok(None) if the key is missing, Err(()) if its value doesn't decode.
+    fn decode<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, ()> {
+        if !self.tree.has_key(key) {
+            return Ok(None);
+        }
+        decode_exact(&self.tree.get_unsafe(key)).map(Some).ok_or(())
+    }
+
+    pub fn check(&self) -> DBResult<Vec<Issue>> {
+        let mut issues = vec![];
+        let listed: Vec<String> = match self.decode("/") {
+            Ok(listed) => listed.ok_or(TableNotFound)?,
+            Err(()) => {
+                issues.push(Issue::CorruptTableList);
+                vec![]
+            }
+        };
+        for t in &listed {
+            let records = self.decode::<Vec<u64>>(&format!("/{}", t));
+            let schema = self.decode::<Schema>(&format!("#{}", t));
+            if records.is_err() {
+                issues.push(Issue::CorruptList { table: t.clone() });
+            }
+            if schema.is_err() {
+                issues.push(Issue::CorruptSchema { table: t.clone() });
+            }
+            let (records, schema) = match (records, schema) {
+                (Ok(Some(r)), Ok(Some(s))) => (r, s),
+                (Ok(_), Ok(_)) => {
+                    issues.push(Issue::MissingTable { table: t.clone() });
+                    continue;
+                },
+                _ => continue
+            };
+            let mut seen = BTreeSet::new();
+            for ident in records {
+                if !seen.insert(ident) {
+                    issues.push(Issue::DuplicateRecord { table: t.clone(), ident });
+                } else if !self.tree.has_key(&record_key(t, ident)) {
+                    issues.push(Issue::MissingRecord { table: t.clone(), ident });
+                } else if !self.row_matches(t, &schema, ident) {
+                    issues.push(Issue::SchemaMismatch { table: t.clone(), ident });
+                }
+            }
+        }
+        for k in self.tree.keys("/") {
+            let t = &k[1..];
+            if k != "/" && self.tree.has_key(&format!("#{}", t)) && !listed.iter().any(|l| l == t) {
+                issues.push(Issue::UnlistedTable { table: t.to_string() });
+            }
+        }
+        Ok(issues)
+    }
+
+    fn unlink(&self, table: &str, ident: u64, mode: RepairMode) {
+        let lk = format!("/{}", table);
+        let mut records: Vec<u64> = self.tree.get_value(&lk).unwrap_or_default();
+        records.retain(|r| *r != ident);
+        self.tree.set_value(&lk, &records);
+        let rk = record_key(table, ident);
+        if mode == RepairMode::Quarantine && self.tree.has_key(&rk) {
+            let q = Quarantined { table: table.to_string(), ident, value: self.tree.get_unsafe(&rk) };
+            self.tree.set_value(&quarantine_key(table, ident), &q);
+        }
+    }
+
+    fn forget_table(&self, table: &str) -> DBResult<()> {
+        let tables: Vec<String> = self.get_tables()?.into_iter().filter(|t| t != table).collect();
+        self.tree.set_value("/", &tables);
+        self.tree.del(&format!("/{}", table));
+        self.tree.del(&format!("#{}", table));
+        for k in self.tree.keys(&records_prefix(table)) {
+            self.tree.del(&k);
+        }
+        Ok(())
+    }
+
+    // Idents of the rows stored for a table, whether listed or not.
+    fn stored_idents(&self, table: &str) -> Vec<u64> {
+        let prefix = records_prefix(table);
+        let mut idents: Vec<u64> = self.tree.keys(&prefix).iter()
+            .filter_map(|k| k[prefix.len()..].parse().ok())
+            .collect();
+        idents.sort();
+        idents
+    }
+
+    fn quarantine_rows(&self, table: &str) {
+        for ident in self.stored_idents(table) {
+            let q = Quarantined { table: table.to_string(), ident, value: self.tree.get_unsafe(&record_key(table, ident)) };
+            self.tree.set_value(&quarantine_key(table, ident), &q);
+        }
+    }
+
+    pub fn repair(&mut self, mode: RepairMode) -> DBResult<CheckReport> {
+        let issues = self.check()?;
+        for issue in &issues {
+            match issue {
+                Issue::MissingRecord { table, ident } => self.unlink(table, *ident, mode),
+                Issue::SchemaMismatch { table, ident } => {
+                    self.unlink(table, *ident, mode);
+                    let rk = record_key(table, *ident);
+                    let schema: Option<Schema> = self.tree.get_value(&format!("#{}", table));
+                    if let (Some(schema), Ok(value)) = (schema, deserialize::<Vec<DBValue>>(&self.tree.get_unsafe(&rk))) {
+                        fts::index_row(&self.tree, table, &schema, *ident, &value, false);
+                    }
+                    self.tree.del(&rk);
+                },
+                Issue::MissingTable { table } => {
+                    if mode == RepairMode::Quarantine {
+                        self.quarantine_rows(table);
+                    }
+                    self.forget_table(table)?;
+                },
+                Issue::UnlistedTable { table } => {
+                    let mut tables = self.get_tables()?;
+                    tables.push(table.clone());
+                    self.tree.set_value("/", &tables);
+                },
+                Issue::DuplicateRecord { table, ident } => {
+                    let lk = format!("/{}", table);
+                    let mut records: Vec<u64> = self.tree.get_value(&lk).unwrap_or_default();
+                    let mut seen = BTreeSet::new();
+                    records.retain(|r| r != ident || seen.insert(*r));
+                    self.tree.set_value(&lk, &records);
+                },
+                Issue::CorruptSchema { table } => {
+                    if mode == RepairMode::Quarantine {
+                        self.quarantine_rows(table);
+                    }
+                    self.forget_table(table)?;
+                },
+                Issue::CorruptList { table } => self.tree.set_value(&format!("/{}", table), &self.stored_idents(table)),
+                Issue::CorruptTableList => self.tree.set_value("/", &Vec::<String>::new())
+            }
+        }
+        let remaining = self.check()?;
+        let repaired = issues.iter().filter(|i| !remaining.contains(i)).count();
+        Ok(CheckReport { issues, repaired })
+    }
+}
+
+pub fn cli(args: &[String]) -> DBResult<CheckReport> {
+    let name = args.get(0).ok_or(DatabaseNotFound)?;
+    let mode = match args.get(1).map(String::as_str) {
+        Some("--repair") => Some(RepairMode::parse(args.get(2).map_or("quarantine", String::as_str))?),
+        Some(_) => return Err(InvalidQuery),
+        None => None
+    };
+    if !catalog::path(name)?.exists() {
+        return Err(DatabaseNotFound);
+    }
+    let mut db = DB::new(name)?;
+    match mode {
+        Some(mode) => db.atomic(|db| db.repair(mode)),
+        None => Ok(CheckReport { issues: db.check()?, repaired: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::getset::MemStore;
+
+    fn setup() -> DB<MemStore> {
+        let mut db = DB::in_memory(MemStore::default());
+        let schema = Schema { columns: vec![Column::new("a", Type::Integer)], ..Schema::default() };
+        for t in &["t", "u"] {
+            db.add_table(t, &schema).unwrap();
+            for i in 0..3 {
+                db.get_table(t).unwrap().add_record(&[DBValue::Integer(i)]).unwrap();
+            }
+        }
+        db
+    }
+
+    #[test]
+    fn repairs_corrupt_list_and_schema() {
+        let mut db = setup();
+        let rows = db.get_table("t").unwrap().get_records().len();
+        db.tree.set_unsafe("/t", vec![1, 2, 3]);
+        db.tree.set_unsafe("#u", vec![0xff]);
+        let issues = db.check().unwrap();
+        assert_eq!(issues, vec![Issue::CorruptList { table: "t".to_string() }, Issue::CorruptSchema { table: "u".to_string() }]);
+
+        let report = db.repair(RepairMode::Quarantine).unwrap();
+        assert_eq!(report.repaired, 2);
+        assert!(db.check().unwrap().is_empty());
+        assert_eq!(db.get_table("t").unwrap().get_records().len(), rows);
+        assert_eq!(db.get_tables().unwrap(), vec!["t".to_string()]);
+        assert_eq!(db.tree.keys("*u/").len(), 3);
+    }
+
+    #[test]
+    fn repairs_corrupt_table_list() {
+        let mut db = setup();
+        db.tree.set_unsafe("/", vec![0xff; 3]);
+        assert_eq!(db.check().unwrap()[0], Issue::CorruptTableList);
+        db.repair(RepairMode::Drop).unwrap();
+        assert!(db.check().unwrap().is_empty());
+        assert_eq!(db.get_tables().unwrap(), vec!["t".to_string(), "u".to_string()]);
+    }
+
+    #[test]
+    fn quarantines_rows_of_missing_table() {
+        let mut db = setup();
+        db.tree.del("#u");
+        assert_eq!(db.check().unwrap(), vec![Issue::MissingTable { table: "u".to_string() }]);
+        db.repair(RepairMode::Quarantine).unwrap();
+        assert!(db.check().unwrap().is_empty());
+        assert_eq!(db.tree.keys("*u/").len(), 3);
+        assert!(db.tree.keys(&records_prefix("u")).is_empty());
+    }
+
+    #[test]
+    fn drops_postings_of_mismatched_rows() {
+        let mut db = DB::in_memory(MemStore::default());
+        let schema = Schema { columns: vec![Column { fulltext: true, ..Column::new("a", Type::Str) }], ..Schema::default() };
+        db.add_table("t", &schema).unwrap();
+        let ident = db.get_table("t").unwrap().add_record(&[DBValue::Str("hello world".to_string())]).unwrap();
+        db.tree.set_value(&record_key("t", ident), &vec![DBValue::Str("hello world".to_string()), DBValue::Integer(1)]);
+        assert_eq!(db.check().unwrap(), vec![Issue::SchemaMismatch { table: "t".to_string(), ident }]);
+
+        db.repair(RepairMode::Drop).unwrap();
+        assert!(db.tree.keys(&fts::table_prefix("t")).is_empty());
+        assert!(db.get_table("t").unwrap().search("hello").is_empty());
+    }
+
+    #[test]
+    fn quarantine_follows_its_table() {
+        let mut db = setup();
+        let ident = db.get_table("t").unwrap().get_records()[0].ident;
+        db.tree.set_value(&record_key("t", ident), &vec![DBValue::Str("x".to_string())]);
+        db.repair(RepairMode::Quarantine).unwrap();
+        assert_eq!(db.tree.keys("*t/").len(), 1);
+
+        db.rename_table("t", "v").unwrap();
+        assert!(db.tree.keys("*t/").is_empty());
+        let q: Quarantined = db.tree.get_value(&quarantine_key("v", ident)).unwrap();
+        assert_eq!(q.table, "v");
+
+        db.remove_table("v", false).unwrap();
+        assert!(db.tree.keys("*v/").is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+
+use serde::de::DeserializeOwned;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::changes::{Change, ChangeKind};
+use crate::db::*;
+use crate::getset::{decode_exact, GetSet};
+use crate::migrate::{Migration, SchemaOp};
+use crate::trigger::Trigger;
+
+// bincode is not self-describing, so every shape `Schema` has been stored in
+// needs its own struct. Shapes are tried newest first and must consume the
+// whole value.
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ColumnV0 {
+    pub name: String,
+    pub ctype: Type,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ColumnV1 {
+    pub name: String,
+    pub ctype: Type,
+    pub nullable: bool,
+    pub references: Option<Reference>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SchemaV0 {
+    pub columns: Vec<ColumnV0>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SchemaV1 {
+    pub columns: Vec<ColumnV1>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SchemaV2 {
+    pub columns: Vec<Column>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SchemaV3 {
+    pub columns: Vec<Column>,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SchemaV4 {
+    pub columns: Vec<Column>,
+    pub checks: Vec<Check>,
+    pub triggers: Vec<Trigger>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SchemaV5 {
+    pub columns: Vec<Column>,
+    pub checks: Vec<Check>,
+    pub triggers: Vec<Trigger>,
+    pub version: u64,
+}
+
+// Migrations and change feed entries embed a schema of their time.
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MigrationAs<S> {
+    pub seq: u64,
+    pub table: String,
+    pub version: u64,
+    pub op: SchemaOp,
+    pub before: S,
+    pub saved: BTreeMap<u64, DBValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChangeAs<S> {
+    pub seq: u64,
+    pub table: String,
+    pub kind: ChangeKind,
+    pub ident: Option<u64>,
+    pub old: Option<Vec<DBValue>>,
+    pub new: Option<Vec<DBValue>>,
+    pub schema: Option<S>,
+}
+
+impl From<ColumnV0> for Column {
+    fn from(c: ColumnV0) -> Column {
+        Column::new(&c.name, c.ctype)
+    }
+}
+
+impl From<ColumnV1> for Column {
+    fn from(c: ColumnV1) -> Column {
+        Column { nullable: c.nullable, references: c.references, ..Column::new(&c.name, c.ctype) }
+    }
+}
+
+impl From<SchemaV0> for Schema {
+    fn from(s: SchemaV0) -> Schema {
+        Schema { columns: s.columns.into_iter().map(Column::from).collect(), ..Schema::default() }
+    }
+}
+
+impl From<SchemaV1> for Schema {
+    fn from(s: SchemaV1) -> Schema {
+        Schema { columns: s.columns.into_iter().map(Column::from).collect(), ..Schema::default() }
+    }
+}
+
+impl From<SchemaV2> for Schema {
+    fn from(s: SchemaV2) -> Schema {
+        Schema { columns: s.columns, ..Schema::default() }
+    }
+}
+
+impl From<SchemaV3> for Schema {
+    fn from(s: SchemaV3) -> Schema {
+        Schema { columns: s.columns, checks: s.checks, ..Schema::default() }
+    }
+}
+
+impl From<SchemaV4> for Schema {
+    fn from(s: SchemaV4) -> Schema {
+        Schema { columns: s.columns, checks: s.checks, triggers: s.triggers, ..Schema::default() }
+    }
+}
+
+impl From<SchemaV5> for Schema {
+    fn from(s: SchemaV5) -> Schema {
+        Schema { columns: s.columns, checks: s.checks, triggers: s.triggers, version: s.version, ..Schema::default() }
+    }
+}
+
+impl<S: Into<Schema>> From<MigrationAs<S>> for Migration {
+    fn from(m: MigrationAs<S>) -> Migration {
+        Migration { seq: m.seq, table: m.table, version: m.version, op: m.op, before: m.before.into(), saved: m.saved }
+    }
+}
+
+impl<S: Into<Schema>> From<ChangeAs<S>> for Change {
+    fn from(c: ChangeAs<S>) -> Change {
+        Change { seq: c.seq, table: c.table, kind: c.kind, ident: c.ident, old: c.old, new: c.new, schema: c.schema.map(Into::into) }
+    }
+}
+
+pub fn decode_schema(bytes: &[u8]) -> Option<Schema> {
+    decode_exact::<Schema>(bytes)
+        .or_else(|| decode_exact::<SchemaV5>(bytes).map(Schema::from))
+        .or_else(|| decode_exact::<SchemaV4>(bytes).map(Schema::from))
+        .or_else(|| decode_exact::<SchemaV3>(bytes).map(Schema::from))
+        .or_else(|| decode_exact::<SchemaV2>(bytes).map(Schema::from))
+        .or_else(|| decode_exact::<SchemaV1>(bytes).map(Schema::from))
+        .or_else(|| decode_exact::<SchemaV0>(bytes).map(Schema::from))
+}
+
+pub fn decode_migration(bytes: &[u8]) -> Option<Migration> {
+    decode_exact::<Migration>(bytes)
+        .or_else(|| decode_exact::<MigrationAs<SchemaV5>>(bytes).map(Migration::from))
+}
+
+pub fn decode_change(bytes: &[u8]) -> Option<Change> {
+    decode_exact::<Change>(bytes)
+        .or_else(|| decode_exact::<ChangeAs<SchemaV5>>(bytes).map(Change::from))
+        .or_else(|| decode_exact::<ChangeAs<SchemaV4>>(bytes).map(Change::from))
+}
+
+fn rewrite<KV: GetSet, T: serde::Serialize + DeserializeOwned>(db: &KV, key: &str, what: &str, decode: fn(&[u8]) -> Option<T>) {
+    let bytes = db.get_unsafe(key);
+    if decode_exact::<T>(&bytes).is_some() {
+        return;
+    }
+    match decode(&bytes) {
+        Some(value) => db.set_unsafe(key, bincode::serialize(&value).unwrap()),
+        None => eprintln!("{} {} has an unknown layout, leaving it as is", what, &key[1..])
+    }
+}
+
+pub fn upgrade_schemas<KV: GetSet>(db: &KV) {
+    for k in db.keys("#") {
+        rewrite(db, &k, "schema", decode_schema);
+    }
+}
+
+pub fn upgrade_entries<KV: GetSet>(db: &KV) {
+    for k in db.keys("&").into_iter().filter(|k| k != "&") {
+        rewrite(db, &k, "migration", decode_migration);
+    }
+    for k in db.keys("^").into_iter().filter(|k| k != "^") {
+        rewrite(db, &k, "change", decode_change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::getset::{EasyGet, MemStore};
+
+    #[test]
+    fn decodes_baseline_schema() {
+        let old = SchemaV0 { columns: vec![ColumnV0 { name: "a".to_string(), ctype: Type::Integer }, ColumnV0 { name: "b".to_string(), ctype: Type::Str }] };
+        let schema = decode_schema(&bincode::serialize(&old).unwrap()).unwrap();
+        assert_eq!(schema.columns.len(), 2);
+        assert_eq!(schema.columns[1].name, "b");
+        assert_eq!(schema.columns[1].ctype, Type::Str);
+        assert!(!schema.columns[0].nullable);
+        assert!(schema.columns[0].references.is_none());
+        assert_eq!(schema.version, 0);
+    }
+
+    #[test]
+    fn decodes_versioned_schema() {
+        let old = SchemaV5 { columns: vec![Column::new("a", Type::Real)], checks: vec![], triggers: vec![], version: 7 };
+        let schema = decode_schema(&bincode::serialize(&old).unwrap()).unwrap();
+        assert_eq!(schema.version, 7);
+        assert_eq!(schema.ids, IdGen::Random);
+    }
+
+    #[test]
+    fn keeps_current_schema() {
+        let current = Schema { columns: vec![Column::new("a", Type::Char)], version: 3, ids: IdGen::Sequence, ..Schema::default() };
+        let schema = decode_schema(&bincode::serialize(&current).unwrap()).unwrap();
+        assert_eq!(schema.version, 3);
+        assert_eq!(schema.ids, IdGen::Sequence);
+    }
+
+    #[test]
+    fn opens_baseline_tree() {
+        let store = MemStore::default();
+        let columns = vec![ColumnV0 { name: "a".to_string(), ctype: Type::Integer }, ColumnV0 { name: "b".to_string(), ctype: Type::Str }];
+        store.set_value("/", &vec!["t".to_string()]);
+        store.set_value("/t", &vec![7u64, 9]);
+        store.set_value("#t", &SchemaV0 { columns });
+        store.set_value("$7", &vec![DBValue::Integer(1), DBValue::Str("x".to_string())]);
+        store.set_value("$9", &vec![DBValue::Integer(2), DBValue::Str("y".to_string())]);
+
+        let mut db = DB::in_memory(store);
+        assert_eq!(db.tree.get_value::<u32>("="), Some(2));
+        assert!(db.tree.keys("$").iter().all(|k| k.starts_with("$t/")));
+        let values: Vec<Vec<DBValue>> = db.get_table("t").unwrap().get_records().into_iter().map(|r| r.value).collect();
+        assert_eq!(values, vec![
+            vec![DBValue::Integer(1), DBValue::Str("x".to_string())],
+            vec![DBValue::Integer(2), DBValue::Str("y".to_string())],
+        ]);
+        let ident = db.get_table("t").unwrap().add_record(&[DBValue::Integer(3), DBValue::Str("z".to_string())]).unwrap();
+        assert!(db.tree.has_key(&record_key("t", ident)));
+    }
+
+    #[test]
+    fn rewrites_embedded_schemas() {
+        let store = MemStore::default();
+        let schema = || SchemaV5 { columns: vec![Column::new("a", Type::Integer)], checks: vec![], triggers: vec![], version: 1 };
+        store.set_value("=", &1u32);
+        store.set_value("/", &vec!["t".to_string()]);
+        store.set_value("/t", &Vec::<u64>::new());
+        store.set_value("#t", &schema());
+        let op = SchemaOp::DelColumn { column: "b".to_string() };
+        store.set_value("&t/00000000000000000001", &MigrationAs { seq: 1, table: "t".to_string(), version: 1, op, before: schema(), saved: BTreeMap::new() });
+        let v4 = SchemaV4 { columns: vec![Column::new("a", Type::Integer)], checks: vec![], triggers: vec![] };
+        store.set_value("^t/00000000000000000001", &ChangeAs { seq: 1, table: "t".to_string(), kind: ChangeKind::Schema, ident: None, old: None, new: None, schema: Some(v4) });
+
+        let mut db = DB::in_memory(store);
+        assert_eq!(db.get_table("t").unwrap().get_info().schema.version, 1);
+        assert_eq!(db.migrations(Some("t"))[0].before.version, 1);
+        let change = db.changes("t", 0).unwrap().remove(0);
+        assert_eq!(change.schema.unwrap().columns[0].name, "a");
+    }
+}
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rocket_contrib::json::JsonValue;
+use serde_derive::{Serialize, Deserialize};
+use ws::{CloseCode, Handler, Message, Sender};
+
+use crate::changes::{self, Change, ChangeKind, Subscriber};
+use crate::db::*;
+use crate::expr::{Expr, is_true};
+use crate::getset::{EasyGet, GetSet};
+
+const POLL: u64 = 15;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Subscribe {
+    db: String,
+    table: String,
+    filter: Option<String>,
+    since: Option<u64>,
+}
+
+struct Session {
+    out: Sender,
+    active: Option<Arc<AtomicBool>>,
+}
+
+struct Subscription {
+    out: Sender,
+    active: Arc<AtomicBool>,
+    filter: Option<Expr>,
+    columns: Vec<String>,
+    _sub: Subscriber,
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    pub fn snapshot(&mut self, table: &str) -> DBResult<(u64, Schema, Vec<Record>)> {
+        let seq = self.tree.get_value("^").unwrap_or(0);
+        let table = self.get_table(table)?;
+        Ok((seq, table.get_info().schema, table.get_records()))
+    }
+}
+
+impl Subscription {
+    fn matches(&self, value: &Option<Vec<DBValue>>) -> bool {
+        match (value, &self.filter) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(v), Some(f)) => f.eval(&self.columns, v).map_or(false, |r| is_true(&r))
+        }
+    }
+
+    fn set_schema(&mut self, schema: &Schema) {
+        self.columns = schema.columns.iter().map(|c| c.name.clone()).collect();
+    }
+
+    fn diff(&mut self, c: &Change) -> Option<JsonValue> {
+        let (old, new) = (self.matches(&c.old), self.matches(&c.new));
+        match (c.kind, old, new) {
+            (ChangeKind::Schema, _, _) => {
+                if let Some(s) = &c.schema {
+                    self.set_schema(s);
+                }
+                Some(json!({"type": "schema", "seq": c.seq, "schema": c.schema}))
+            },
+            (ChangeKind::Drop, _, _) => Some(json!({"type": "drop", "seq": c.seq})),
+            (_, true, true) => Some(json!({"type": "update", "seq": c.seq, "ident": c.ident, "old": c.old, "new": c.new})),
+            (_, false, true) => Some(json!({"type": "insert", "seq": c.seq, "ident": c.ident, "value": c.new})),
+            (_, true, false) => Some(json!({"type": "delete", "seq": c.seq, "ident": c.ident, "value": c.old})),
+            (_, false, false) => None
+        }
+    }
+
+    fn send(&self, msg: JsonValue) -> bool {
+        self.out.send(msg.to_string()).is_ok()
+    }
+
+    fn run(mut self, req: Subscribe) -> DBResult<()> {
+        let (mut seq, schema, records, replay) = {
+            let mut dbs = DATABASES.lock().unwrap();
+            let db = get_db(&mut *dbs, &req.db)?;
+            let (seq, schema, records) = db.snapshot(&req.table)?;
+            // Changes from before a schema change can't be filtered against
+            // the current columns, so such subscribers get a fresh snapshot.
+            let replay = match req.since {
+                Some(since) => db.changes(&req.table, since)?.iter().all(|c| c.kind != ChangeKind::Schema),
+                None => false
+            };
+            (seq, schema, records, replay)
+        };
+        self.set_schema(&schema);
+        match req.since {
+            Some(since) if replay => seq = since,
+            _ => {
+                let records: Vec<Record> = records.into_iter()
+                    .filter(|r| self.matches(&Some(r.value.clone())))
+                    .collect();
+                if !self.send(json!({"type": "snapshot", "seq": seq, "schema": schema, "records": records})) {
+                    return Ok(());
+                }
+            }
+        }
+        while self.active.load(Ordering::SeqCst) {
+            for c in changes::poll(&req.db, &req.table, seq, Duration::from_secs(POLL))? {
+                seq = c.seq;
+                if let Some(msg) = self.diff(&c) {
+                    if !self.send(msg) || c.kind == ChangeKind::Drop {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    fn stop(&mut self) {
+        if let Some(active) = self.active.take() {
+            active.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn error(&self, e: DBError) -> ws::Result<()> {
+        self.out.send(json!({"type": "error", "error": e}).to_string())
+    }
+}
+
+impl Handler for Session {
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let req: Subscribe = match serde_json::from_str(msg.as_text()?) {
+            Ok(req) => req,
+            Err(_) => return self.error(DBError::InvalidQuery)
+        };
+        let filter = match req.filter.as_ref().map(|f| Expr::parse(f)) {
+            Some(Err(e)) => return self.error(e),
+            Some(Ok(f)) => Some(f),
+            None => None
+        };
+        self.stop();
+        let active = Arc::new(AtomicBool::new(true));
+        self.active = Some(active.clone());
+        let sub = Subscription { out: self.out.clone(), active, filter, columns: vec![], _sub: Subscriber::new(&req.db) };
+        let out = self.out.clone();
+        thread::spawn(move || {
+            if let Err(e) = sub.run(req) {
+                let _ = out.send(json!({"type": "error", "error": e}).to_string());
+            }
+        });
+        Ok(())
+    }
+
+    fn on_close(&mut self, _code: CloseCode, _reason: &str) {
+        self.stop();
+    }
+}
+
+pub fn serve(addr: String) {
+    thread::spawn(move || {
+        if let Err(e) = ws::listen(addr.as_str(), |out| Session { out, active: None }) {
+            eprintln!("live queries failed on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    });
+}
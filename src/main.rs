@@ -19,12 +19,28 @@ extern crate serde;
 extern crate sled;
 
 extern crate serde_json;
+extern crate unicode_normalization;
+extern crate ws;
 
 extern crate problem;
 
+mod backup;
+mod catalog;
+mod changes;
 mod db;
+mod export;
+mod expr;
+mod fts;
 mod getset;
+mod integrity;
+mod legacy;
+mod live;
+mod matview;
+mod migrate;
+mod query;
 mod routes;
+mod trigger;
+mod vacuum;
 
 use rocket::http::Method;
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
@@ -49,6 +65,17 @@ pub struct Test {
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("check") {
+        match integrity::cli(&args[2..]) {
+            Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+            Err(e) => {
+                eprintln!("check failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     let cors = rocket_cors::Cors {
         allowed_origins: AllowedOrigins::all(),
         allowed_headers: AllowedHeaders::some(&["Content-Type"]),
@@ -56,6 +83,8 @@ fn main() {
         allow_credentials: true,
         ..rocket_cors::Cors::default()
     };
+    catalog::open_all();
+    live::serve(std::env::var("DB_WS_ADDR").unwrap_or_else(|_| "127.0.0.1:8001".to_string()));
     rocket::ignite()
         .mount("/db", routes::ROUTES.clone())
         .attach(cors)
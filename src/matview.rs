@@ -0,0 +1,482 @@
+use std::collections::BTreeMap;
+use std::cmp::Ordering;
+
+use bincode::serialize;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::expr::{Projection, is_true};
+use crate::getset::{EasyGet, GetSet};
+use crate::query::{AggFunc, AggQuery, Query, ResultSet, aggregate};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MatView {
+    pub query: Query,
+    #[serde(default)]
+    pub aggregate: Option<AggQuery>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct MatState {
+    stale: bool,
+    rows: BTreeMap<u64, u64>,
+    groups: BTreeMap<Vec<u8>, Group>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Group {
+    ident: Option<u64>,
+    key: Vec<DBValue>,
+    rows: i64,
+    states: Vec<(DBValue, i64)>,
+    distinct: Vec<BTreeMap<Vec<u8>, i64>>,
+}
+
+fn def_key(name: &str) -> String {
+    format!("~{}", name)
+}
+
+fn state_key(name: &str) -> String {
+    format!("!{}", name)
+}
+
+pub fn get_matviews<KV: GetSet>(db: &KV) -> Vec<String> {
+    db.get_value("~").unwrap_or_default()
+}
+
+pub fn is_matview<KV: GetSet>(db: &KV, name: &str) -> bool {
+    db.has_key(&def_key(name))
+}
+
+pub fn dependents<KV: GetSet>(db: &KV, table: &str) -> Vec<String> {
+    get_matviews(db).into_iter()
+        .filter(|m| db.get_value::<MatView>(&def_key(m)).map_or(false, |d| d.query.sources().contains(&table)))
+        .collect()
+}
+
+pub fn forget<KV: GetSet>(db: &KV, name: &str) {
+    let views: Vec<String> = get_matviews(db).into_iter().filter(|m| m != name).collect();
+    db.set_value("~", &views);
+    db.del(&def_key(name));
+    db.del(&state_key(name));
+}
+
+pub fn define<KV: GetSet>(db: &KV, name: &str, def: &MatView) {
+    db.set_value(&def_key(name), def);
+    let mut views = get_matviews(db);
+    views.push(name.to_string());
+    db.set_value("~", &views);
+}
+
+pub fn rename<KV: GetSet>(db: &KV, old: &str, new: &str) {
+    for m in get_matviews(db) {
+        if let Some(mut def) = db.get_value::<MatView>(&def_key(&m)) {
+            def.query.retarget(old, new);
+            db.set_value(&def_key(&m), &def);
+        }
+    }
+    if !is_matview(db, old) {
+        return;
+    }
+    let views: Vec<String> = get_matviews(db).into_iter()
+        .map(|m| if m == old { new.to_string() } else { m })
+        .collect();
+    db.set_value("~", &views);
+    for (from, to) in &[(def_key(old), def_key(new)), (state_key(old), state_key(new))] {
+        if db.has_key(from) {
+            db.set_unsafe(to, db.get_unsafe(from));
+            db.del(from);
+        }
+    }
+}
+
+pub fn status<KV: GetSet>(db: &KV, name: &str) -> DBResult<(MatView, bool)> {
+    let def = db.get_value(&def_key(name)).ok_or(TableNotFound)?;
+    let stale = db.get_value::<MatState>(&state_key(name)).map_or(false, |s| s.stale);
+    Ok((def, stale))
+}
+
+pub fn invalidate<KV: GetSet>(db: &KV, table: &str) {
+    for m in dependents(db, table) {
+        let mut state: MatState = db.get_value(&state_key(&m)).unwrap_or_default();
+        state.stale = true;
+        db.set_value(&state_key(&m), &state);
+    }
+}
+
+impl MatView {
+    fn incremental(&self) -> bool {
+        self.query.joins.is_empty() && self.aggregate.as_ref().map_or(true, |a| a.having.is_none())
+    }
+
+    fn project(&self, columns: &[String], row: &[DBValue]) -> DBResult<Option<Vec<DBValue>>> {
+        if let Some(f) = &self.query.filter {
+            if !is_true(&f.eval(columns, row)?) {
+                return Ok(None);
+            }
+        }
+        match &self.query.select {
+            Some(select) => select.iter()
+                .map(|p| p.expr.eval(columns, row))
+                .collect::<DBResult<Vec<_>>>()
+                .map(Some),
+            None => Ok(Some(row.to_vec()))
+        }
+    }
+}
+
+fn fit(schema: &Schema, row: Vec<DBValue>) -> DBResult<Vec<DBValue>> {
+    row.into_iter()
+        .zip(&schema.columns)
+        .map(|(v, c)| v.coerce(&c.ctype).ok_or(TypeMismatch))
+        .collect()
+}
+
+fn add_signed(acc: &DBValue, v: &DBValue, sign: i64) -> DBResult<DBValue> {
+    match (acc, v) {
+        (DBValue::Null, DBValue::Integer(b)) => b.checked_mul(sign).map(DBValue::Integer).ok_or(Overflow),
+        (DBValue::Null, DBValue::Real(b)) => Ok(DBValue::Real(b * sign as f64)),
+        (DBValue::Integer(a), DBValue::Integer(b)) =>
+            b.checked_mul(sign).and_then(|d| a.checked_add(d)).map(DBValue::Integer).ok_or(Overflow),
+        (DBValue::Integer(a), DBValue::Real(b)) => Ok(DBValue::Real(*a as f64 + b * sign as f64)),
+        (DBValue::Real(a), DBValue::Integer(b)) => Ok(DBValue::Real(a + *b as f64 * sign as f64)),
+        (DBValue::Real(a), DBValue::Real(b)) => Ok(DBValue::Real(a + b * sign as f64)),
+        _ => Err(TypeMismatch)
+    }
+}
+
+impl Group {
+    fn new(key: Vec<DBValue>, n: usize) -> Group {
+        Group {
+            ident: None,
+            key,
+            rows: 0,
+            states: vec![(DBValue::Null, 0); n],
+            distinct: vec![BTreeMap::new(); n]
+        }
+    }
+
+    fn apply(&mut self, agg: &AggQuery, cols: &[Option<usize>], row: &[DBValue], sign: i64) -> DBResult<bool> {
+        let mut recompute = false;
+        self.rows += sign;
+        for (((a, col), st), seen) in agg.aggregates.iter().zip(cols).zip(self.states.iter_mut()).zip(self.distinct.iter_mut()) {
+            let v = match col {
+                Some(c) => &row[*c],
+                None => &DBValue::Integer(1)
+            };
+            if *v == DBValue::Null {
+                continue;
+            }
+            st.1 += sign;
+            match a.func {
+                AggFunc::Count => (),
+                AggFunc::Sum | AggFunc::Avg => st.0 = add_signed(&st.0, v, sign)?,
+                AggFunc::Min | AggFunc::Max => {
+                    let want = if a.func == AggFunc::Min { Ordering::Less } else { Ordering::Greater };
+                    if sign < 0 {
+                        recompute |= st.0.total_cmp(v, Collation::Byte) == Ordering::Equal;
+                    } else if st.0 == DBValue::Null || v.total_cmp(&st.0, Collation::Byte) == want {
+                        st.0 = v.clone();
+                    }
+                },
+                AggFunc::CountDistinct => {
+                    let k = serialize(v).unwrap();
+                    let n = *seen.get(&k).unwrap_or(&0) + sign;
+                    if n > 0 {
+                        seen.insert(k, n);
+                    } else {
+                        seen.remove(&k);
+                    }
+                    st.0 = DBValue::Integer(seen.len() as i64);
+                },
+            }
+        }
+        Ok(recompute)
+    }
+
+    fn output(&self, agg: &AggQuery) -> Vec<DBValue> {
+        let mut row = self.key.clone();
+        for (a, (v, n)) in agg.aggregates.iter().zip(&self.states) {
+            row.push(match a.func {
+                AggFunc::Count => DBValue::Integer(*n),
+                _ if *n == 0 => DBValue::Null,
+                AggFunc::Avg => match v {
+                    DBValue::Integer(i) => DBValue::Real(*i as f64 / *n as f64),
+                    DBValue::Real(f) => DBValue::Real(f / *n as f64),
+                    _ => DBValue::Null
+                },
+                _ => v.clone()
+            });
+        }
+        row
+    }
+}
+
+struct Source {
+    columns: Vec<String>,
+    keys: Vec<usize>,
+    cols: Vec<Option<usize>>,
+}
+
+impl Source {
+    fn new(schema: &Schema, def: &MatView) -> DBResult<Source> {
+        let columns: Vec<String> = schema.columns.iter().map(|c| c.name.clone()).collect();
+        let output: Vec<String> = match &def.query.select {
+            Some(select) => select.iter().map(Projection::name).collect(),
+            None => columns.clone()
+        };
+        let find = |c: &str| output.iter().position(|x| x == c).ok_or(InvalidColumn);
+        let agg = def.aggregate.as_ref();
+        let (keys, cols) = match agg {
+            Some(agg) => (
+                agg.group_by.iter().map(|c| find(c)).collect::<DBResult<Vec<_>>>()?,
+                agg.aggregates.iter()
+                    .map(|a| match &a.column {
+                        Some(c) => find(c).map(Some),
+                        None => Ok(None)
+                    })
+                    .collect::<DBResult<Vec<_>>>()?
+            ),
+            None => (vec![], vec![])
+        };
+        Ok(Source { columns, keys, cols })
+    }
+}
+
+fn recompute<KV: GetSet>(db: &mut KV, def: &MatView, agg: &AggQuery, src: &Source, group: &mut Group) -> DBResult<()> {
+    let rows: Vec<Vec<DBValue>> = Table::load(&def.query.from, &mut *db)?.get_records().into_iter()
+        .map(|r| r.value)
+        .collect();
+    let mut fresh = Group::new(group.key.clone(), agg.aggregates.len());
+    fresh.ident = group.ident;
+    for row in rows {
+        let row = match def.project(&src.columns, &row)? {
+            Some(r) => r,
+            None => continue
+        };
+        if src.keys.iter().map(|&k| &row[k]).eq(group.key.iter()) {
+            fresh.apply(agg, &src.cols, &row, 1)?;
+        }
+    }
+    *group = fresh;
+    Ok(())
+}
+
+fn apply_change<KV: GetSet>(db: &mut KV, name: &str, def: &MatView, state: &mut MatState, ident: u64, old: Option<&[DBValue]>, new: Option<&[DBValue]>) -> DBResult<()> {
+    let schema: Schema = db.get_value(&format!("#{}", def.query.from)).ok_or(TableNotFound)?;
+    let src = Source::new(&schema, def)?;
+    let agg = match &def.aggregate {
+        Some(agg) => agg,
+        None => {
+            let row = match new {
+                Some(n) => def.project(&src.columns, n)?,
+                None => None
+            };
+            let mut target = Table::load(name, &mut *db)?;
+            target.readonly = false;
+            match (state.rows.get(&ident).cloned(), row) {
+                (Some(m), Some(r)) => target.upd_record(m, &fit(&target.schema, r)?)?,
+                (Some(m), None) => {
+                    target.del_record(m)?;
+                    state.rows.remove(&ident);
+                },
+                (None, Some(r)) => {
+                    let m = target.add_record(&fit(&target.schema, r)?)?;
+                    state.rows.insert(ident, m);
+                },
+                (None, None) => ()
+            }
+            return Ok(());
+        }
+    };
+
+    let mut touched = vec![];
+    for (row, sign) in old.into_iter().map(|o| (o, -1)).chain(new.into_iter().map(|n| (n, 1))) {
+        let row = match def.project(&src.columns, row)? {
+            Some(r) => r,
+            None => continue
+        };
+        let key: Vec<DBValue> = src.keys.iter().map(|&k| row[k].clone()).collect();
+        let hk = serialize(&key).unwrap();
+        let group = state.groups.entry(hk.clone())
+            .or_insert_with(|| Group::new(key, agg.aggregates.len()));
+        let redo = group.apply(agg, &src.cols, &row, sign)?;
+        touched.push((hk, redo));
+    }
+
+    for (hk, redo) in touched {
+        let mut group = match state.groups.remove(&hk) {
+            Some(g) => g,
+            None => continue
+        };
+        // Without group_by the single group always has a row, as in a full refresh.
+        let keep = group.rows > 0 || agg.group_by.is_empty();
+        if redo && group.rows > 0 {
+            recompute(db, def, agg, &src, &mut group)?;
+        }
+        let mut target = Table::load(name, &mut *db)?;
+        target.readonly = false;
+        match (group.ident, keep) {
+            (Some(m), true) => target.upd_record(m, &fit(&target.schema, group.output(agg))?)?,
+            (None, true) => group.ident = Some(target.add_record(&fit(&target.schema, group.output(agg))?)?),
+            (Some(m), false) => target.del_record(m)?,
+            (None, false) => ()
+        }
+        if keep {
+            state.groups.insert(hk, group);
+        }
+    }
+    Ok(())
+}
+
+pub fn maintain<KV: GetSet>(db: &mut KV, source: &str, ident: u64, old: Option<&[DBValue]>, new: Option<&[DBValue]>) -> DBResult<()> {
+    for name in dependents(&*db, source) {
+        let def: MatView = db.get_value(&def_key(&name)).ok_or(TableNotFound)?;
+        let mut state: MatState = db.get_value(&state_key(&name)).unwrap_or_default();
+        if !def.incremental() {
+            state.stale = true;
+        } else if !state.stale {
+            apply_change(db, &name, &def, &mut state, ident, old, new)?;
+        }
+        db.set_value(&state_key(&name), &state);
+    }
+    Ok(())
+}
+
+fn result_schema<KV: GetSet>(db: &mut DB<KV>, def: &MatView) -> DBResult<(Schema, ResultSet)> {
+    let (schema, res) = db.run_query(&def.query)?;
+    let agg = match &def.aggregate {
+        Some(agg) => agg,
+        None => return Ok((schema, res))
+    };
+    let find = |c: &str| schema.columns.iter().find(|x| x.name == c).cloned().ok_or(InvalidColumn);
+    let mut columns = agg.group_by.iter().map(|c| find(c)).collect::<DBResult<Vec<_>>>()?;
+    for a in &agg.aggregates {
+        let ctype = match (a.func, &a.column) {
+            (AggFunc::Count, _) | (AggFunc::CountDistinct, _) => Type::Integer,
+            (AggFunc::Avg, _) => Type::Real,
+            (_, Some(c)) => find(c)?.ctype,
+            (_, None) => return Err(InvalidQuery)
+        };
+        columns.push(Column { nullable: true, ..Column::new(&a.name(), ctype) });
+    }
+    let res = aggregate(&res, agg)?;
+    Ok((Schema { columns, ..Schema::default() }, res))
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    pub fn add_matview(&mut self, name: &str, def: &MatView) -> DBResult<()> {
+        let views = self.get_views();
+        if def.query.sources().iter().any(|s| views.iter().any(|v| v == s)) {
+            return Err(InvalidQuery);
+        }
+        let (schema, _) = result_schema(self, def)?;
+        self.add_table(name, &schema)?;
+        define(&self.tree, name, def);
+        self.refresh_matview(name)
+    }
+
+    pub fn refresh_matview(&mut self, name: &str) -> DBResult<()> {
+        let def: MatView = self.tree.get_value(&def_key(name)).ok_or(TableNotFound)?;
+        {
+            let mut target = Table::load(name, &mut self.tree)?;
+            target.readonly = false;
+            for ident in target.get_records().into_iter().map(|r| r.ident) {
+                target.del_record(ident)?;
+            }
+        }
+        let mut state = MatState::default();
+        if def.incremental() {
+            let records = Table::load(&def.query.from, &mut self.tree)?.get_records();
+            for r in records {
+                apply_change(&mut self.tree, name, &def, &mut state, r.ident, None, Some(&r.value))?;
+            }
+            if let Some(agg) = def.aggregate.as_ref().filter(|a| a.group_by.is_empty() && state.groups.is_empty()) {
+                let mut target = Table::load(name, &mut self.tree)?;
+                target.readonly = false;
+                let mut group = Group::new(vec![], agg.aggregates.len());
+                group.ident = Some(target.add_record(&fit(&target.schema, group.output(agg))?)?);
+                state.groups.insert(serialize(&group.key).unwrap(), group);
+            }
+        } else {
+            let (_, res) = result_schema(self, &def)?;
+            let mut target = Table::load(name, &mut self.tree)?;
+            target.readonly = false;
+            for row in res.records {
+                target.add_record(&fit(&target.schema, row)?)?;
+            }
+        }
+        self.tree.set_value(&state_key(name), &state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::getset::MemStore;
+    use crate::query::parse_aggregates;
+
+    fn setup(group_by: &[&str], aggregates: &str) -> DB<MemStore> {
+        let mut db = DB::in_memory(MemStore::default());
+        let schema = Schema { columns: vec![Column::new("g", Type::Str), Column::new("qty", Type::Integer)], ..Schema::default() };
+        db.add_table("sales", &schema).unwrap();
+        let def = MatView {
+            query: Query { from: "sales".to_string(), alias: None, joins: vec![], filter: None, select: None },
+            aggregate: Some(AggQuery {
+                group_by: group_by.iter().map(|c| c.to_string()).collect(),
+                aggregates: parse_aggregates(aggregates).unwrap(),
+                having: None,
+            }),
+        };
+        db.add_matview("totals", &def).unwrap();
+        db
+    }
+
+    fn rows(db: &mut DB<MemStore>) -> Vec<Vec<DBValue>> {
+        let mut rows: Vec<Vec<DBValue>> = db.get_table("totals").unwrap().get_records().into_iter().map(|r| r.value).collect();
+        rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        rows
+    }
+
+    fn full(db: &mut DB<MemStore>) -> Vec<Vec<DBValue>> {
+        db.refresh_matview("totals").unwrap();
+        rows(db)
+    }
+
+    fn sale(g: &str, qty: i64) -> Vec<DBValue> {
+        vec![DBValue::Str(g.to_string()), DBValue::Integer(qty)]
+    }
+
+    #[test]
+    fn empty_source_without_group_by() {
+        let mut db = setup(&[], "count(*), sum(qty)");
+        let empty = vec![vec![DBValue::Integer(0), DBValue::Null]];
+        assert_eq!(rows(&mut db), empty);
+        assert_eq!(full(&mut db), empty);
+
+        let ident = db.get_table("sales").unwrap().add_record(&sale("a", 5)).unwrap();
+        assert_eq!(rows(&mut db), vec![vec![DBValue::Integer(1), DBValue::Integer(5)]]);
+        db.get_table("sales").unwrap().del_record(ident).unwrap();
+        assert_eq!(rows(&mut db), empty);
+        assert_eq!(full(&mut db), empty);
+    }
+
+    #[test]
+    fn incremental_matches_full_refresh() {
+        let mut db = setup(&["g"], "count(*), sum(qty), min(qty), max(qty)");
+        let mut idents = vec![];
+        for (g, qty) in &[("a", 4), ("b", 7), ("a", 9), ("c", 1), ("b", 2)] {
+            idents.push(db.get_table("sales").unwrap().add_record(&sale(g, *qty)).unwrap());
+        }
+        db.get_table("sales").unwrap().upd_record(idents[1], &sale("a", 3)).unwrap();
+        db.get_table("sales").unwrap().del_record(idents[0]).unwrap();
+        db.get_table("sales").unwrap().del_record(idents[3]).unwrap();
+
+        let incremental = rows(&mut db);
+        assert_eq!(incremental.len(), 2);
+        assert_eq!(incremental, full(&mut db));
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::getset::{EasyGet, GetSet};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum SchemaOp {
+    AddColumn {
+        column: Column,
+        #[serde(default)]
+        index: Option<usize>,
+    },
+    DelColumn {
+        column: String,
+    },
+    MoveColumn {
+        column: String,
+        index: usize,
+    },
+    UpdColumn {
+        column: String,
+        new: Column,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Migration {
+    pub seq: u64,
+    pub table: String,
+    pub version: u64,
+    pub op: SchemaOp,
+    pub before: Schema,
+    pub saved: BTreeMap<u64, DBValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OpReport {
+    pub rows: usize,
+    pub failures: usize,
+    pub error: Option<DBError>,
+}
+
+fn migration_key(table: &str, version: u64) -> String {
+    format!("&{}/{:020}", table, version)
+}
+
+pub fn record<KV: GetSet>(db: &KV, table: &str, version: u64, op: SchemaOp, before: Schema, saved: BTreeMap<u64, DBValue>) {
+    let seq = db.get_value::<u64>("&").unwrap_or(0) + 1;
+    db.set_value("&", &seq);
+    let m = Migration { seq, table: table.to_string(), version, op, before, saved };
+    db.set_value(&migration_key(table, version), &m);
+}
+
+pub fn forget<KV: GetSet>(db: &KV, table: &str) {
+    for k in db.keys(&format!("&{}/", table)) {
+        db.del(&k);
+    }
+}
+
+pub fn rename<KV: GetSet>(db: &KV, old: &str, new: &str) {
+    let prefix = format!("&{}/", old);
+    for k in db.keys(&prefix) {
+        if let Some(mut m) = db.get_value::<Migration>(&k) {
+            m.table = new.to_string();
+            db.set_value(&migration_key(new, m.version), &m);
+        }
+        db.del(&k);
+    }
+}
+
+impl SchemaOp {
+    pub fn apply(&self, table: &mut dyn ITable) -> DBResult<()> {
+        match self {
+            SchemaOp::AddColumn { column, index } => table.add_column(column, *index),
+            SchemaOp::DelColumn { column } => table.del_column(column.clone()),
+            SchemaOp::MoveColumn { column, index } => table.move_column(column.clone(), *index),
+            SchemaOp::UpdColumn { column, new } => table.upd_column(column.clone(), new),
+        }
+    }
+
+    fn failures(&self, schema: &Schema, records: &[Record]) -> usize {
+        match self {
+            SchemaOp::UpdColumn { column, new } => match schema.columns.iter().position(|c| c.name == *column) {
+                Some(idx) => records.iter()
+                    .filter(|r| r.value[idx].coerce(&new.ctype).map_or(true, |v| !new.accepts(&v)))
+                    .count(),
+                None => 0
+            },
+            _ => 0
+        }
+    }
+}
+
+fn position(schema: &Schema, column: &str) -> DBResult<usize> {
+    schema.columns.iter().position(|c| c.name == column).ok_or(InvalidColumn)
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    pub fn migrations(&self, table: Option<&str>) -> Vec<Migration> {
+        let prefix = table.map_or("&".to_string(), |t| format!("&{}/", t));
+        let mut log: Vec<Migration> = self.tree.keys(&prefix).into_iter()
+            .filter(|k| k != "&")
+            .filter_map(|k| self.tree.get_value(&k))
+            .collect();
+        log.sort_by_key(|m| m.seq);
+        log
+    }
+
+    pub fn migrate(&mut self, table: &str, ops: &[SchemaOp], dry_run: bool) -> DBResult<Vec<OpReport>> {
+        let mut report = vec![];
+        for op in ops {
+            let (rows, failures) = {
+                let t = Table::load(table, &mut self.tree)?;
+                let records = t.get_records();
+                (records.len(), op.failures(&t.schema, &records))
+            };
+            let res = op.apply(&mut *self.get_table(table)?);
+            match (res, dry_run) {
+                (Err(e), true) => {
+                    report.push(OpReport { rows, failures, error: Some(e) });
+                    break;
+                },
+                (res, _) => {
+                    res?;
+                    report.push(OpReport { rows, failures, error: None });
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    pub fn revert_schema(&mut self, table: &str, version: u64) -> DBResult<u64> {
+        let current = Table::load(table, &mut self.tree)?.schema.version;
+        if version >= current {
+            return Err(InvalidQuery);
+        }
+        let history = (version + 1..=current)
+            .map(|v| self.tree.get_value::<Migration>(&migration_key(table, v)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(InvalidQuery)?;
+        for m in history.into_iter().rev() {
+            let mut t = Table::load(table, &mut self.tree)?;
+            t.reverting = true;
+            match &m.op {
+                SchemaOp::AddColumn { column, .. } => {
+                    let idx = position(&t.schema, &column.name)?;
+                    let fill = if column.nullable { DBValue::Null } else { column.ctype.defvalue() };
+                    if t.get_records().iter().any(|r| r.value[idx] != fill) {
+                        return Err(LossyRevert);
+                    }
+                    t.del_column(column.name.clone())?;
+                },
+                SchemaOp::DelColumn { column } => {
+                    let idx = position(&m.before, column)?;
+                    t.add_column(&m.before.columns[idx], Some(idx))?;
+                    t.restore_values(idx, &m.saved)?;
+                },
+                SchemaOp::MoveColumn { column, .. } => {
+                    let idx = position(&m.before, column)?;
+                    t.move_column(column.clone(), idx)?;
+                },
+                SchemaOp::UpdColumn { column, new } => {
+                    let old = m.before.columns[position(&m.before, column)?].clone();
+                    let idx = position(&t.schema, &new.name)?;
+                    let mut restored = BTreeMap::new();
+                    for Record { ident, value } in t.get_records() {
+                        let v = &value[idx];
+                        let back = match m.saved.get(&ident) {
+                            Some(s) if s.coerce(&new.ctype).as_ref() == Some(v) => s.clone(),
+                            _ => match v.coerce(&old.ctype) {
+                                Some(b) if b.coerce(&new.ctype).as_ref() == Some(v) => b,
+                                _ => return Err(LossyRevert)
+                            }
+                        };
+                        restored.insert(ident, back);
+                    }
+                    t.upd_column(new.name.clone(), &old)?;
+                    t.restore_values(idx, &restored)?;
+                }
+            }
+        }
+        // The reverted steps are dropped from the log and the table reports a
+        // single schema change.
+        for v in version + 1..=current {
+            self.tree.del(&migration_key(table, v));
+        }
+        Table::load(table, &mut self.tree)?.reverted(version);
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changes::{self, ChangeKind};
+    use crate::getset::MemStore;
+
+    fn columns(db: &mut DB<MemStore>) -> Vec<(String, Type)> {
+        Table::load("t", &mut db.tree).unwrap().schema.columns.iter().map(|c| (c.name.clone(), c.ctype.clone())).collect()
+    }
+
+    fn values(db: &mut DB<MemStore>) -> Vec<Vec<DBValue>> {
+        Table::load("t", &mut db.tree).unwrap().get_records().into_iter().map(|r| r.value).collect()
+    }
+
+    #[test]
+    fn revert_round_trips() {
+        let mut db = DB::in_memory(MemStore::default());
+        db.add_table("t", &Schema { columns: vec![Column::new("a", Type::Integer)], ..Schema::default() }).unwrap();
+        for i in 1..4 {
+            db.get_table("t").unwrap().add_record(&[DBValue::Integer(i)]).unwrap();
+        }
+        let original = (columns(&mut db), values(&mut db));
+
+        let ops = vec![
+            SchemaOp::AddColumn { column: Column { nullable: true, ..Column::new("b", Type::Str) }, index: None },
+            SchemaOp::UpdColumn { column: "a".to_string(), new: Column::new("a", Type::Str) },
+            SchemaOp::MoveColumn { column: "b".to_string(), index: 0 },
+        ];
+        db.migrate("t", &ops, false).unwrap();
+        assert_eq!(db.migrations(Some("t")).len(), 3);
+        assert_eq!(columns(&mut db), vec![("b".to_string(), Type::Str), ("a".to_string(), Type::Str)]);
+
+        let seq = db.tree.get_value::<u64>("^").unwrap();
+        assert_eq!(db.revert_schema("t", 1).unwrap(), 1);
+        assert_eq!(Table::load("t", &mut db.tree).unwrap().schema.version, 1);
+        assert_eq!(db.migrations(Some("t")).len(), 1);
+        assert_eq!(columns(&mut db), vec![("a".to_string(), Type::Integer), ("b".to_string(), Type::Str)]);
+        let logged: Vec<ChangeKind> = changes::since(&db.tree, "t", seq).into_iter().map(|c| c.kind).collect();
+        assert_eq!(logged, vec![ChangeKind::Schema]);
+
+        assert_eq!(db.revert_schema("t", 0).unwrap(), 0);
+        assert!(db.migrations(Some("t")).is_empty());
+        assert_eq!((columns(&mut db), values(&mut db)), original);
+        match db.revert_schema("t", 0) {
+            Err(InvalidQuery) => (),
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+}
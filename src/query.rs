@@ -0,0 +1,733 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+
+use bincode::serialize;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::expr::{Expr, Projection, is_true, parse_projection};
+use crate::fts;
+use crate::getset::GetSet;
+use crate::trigger::Trigger;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Cross,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JoinOn {
+    pub left: String,
+    pub right: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Join {
+    pub table: String,
+    #[serde(default)]
+    pub alias: Option<String>,
+    pub kind: JoinKind,
+    #[serde(default)]
+    pub on: Option<JoinOn>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JoinQuery {
+    pub table: String,
+    #[serde(default)]
+    pub alias: Option<String>,
+    pub joins: Vec<Join>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Query {
+    pub from: String,
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub joins: Vec<Join>,
+    #[serde(default)]
+    pub filter: Option<Expr>,
+    #[serde(default)]
+    pub select: Option<Vec<Projection>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    Union,
+    Intersect,
+    Difference,
+    Product,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SetQuery {
+    pub op: SetOp,
+    pub left: String,
+    pub right: String,
+    #[serde(default)]
+    pub distinct: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AggFunc {
+    Count,
+    CountDistinct,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Aggregate {
+    pub func: AggFunc,
+    pub column: Option<String>,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AggQuery {
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    pub aggregates: Vec<Aggregate>,
+    #[serde(default)]
+    pub having: Option<Expr>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub records: Vec<Vec<DBValue>>,
+}
+
+impl ResultSet {
+    pub fn from_records(schema: &Schema, records: Vec<Record>) -> ResultSet {
+        ResultSet {
+            columns: schema.columns.iter().map(|c| c.name.clone()).collect(),
+            records: records.into_iter().map(|r| r.value).collect()
+        }
+    }
+
+    pub fn column(&self, name: &str) -> DBResult<usize> {
+        self.columns.iter().position(|c| c == name).ok_or(InvalidColumn)
+    }
+}
+
+pub fn project(res: &ResultSet, projection: &[Projection]) -> DBResult<ResultSet> {
+    let records = res.records.iter()
+        .map(|r| projection.iter()
+            .map(|p| p.expr.eval(&res.columns, r))
+            .collect::<DBResult<Vec<_>>>())
+        .collect::<DBResult<Vec<_>>>()?;
+    Ok(ResultSet {
+        columns: projection.iter().map(Projection::name).collect(),
+        records
+    })
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    pub fn scan_table(&mut self, name: &str, alias: Option<&str>) -> DBResult<ResultSet> {
+        let prefix = alias.unwrap_or(name);
+        let table = self.get_table(name)?;
+        let columns = table.get_info().schema.columns.iter()
+            .map(|c| format!("{}.{}", prefix, c.name))
+            .collect();
+        let records = table.get_records().into_iter()
+            .map(|r| r.value)
+            .collect();
+        Ok(ResultSet { columns, records })
+    }
+
+    pub fn materialize(&mut self, name: &str, schema: &Schema, records: &[Vec<DBValue>]) -> DBResult<()> {
+        self.add_table(name, schema)?;
+        let mut table = self.get_table(name)?;
+        for r in records {
+            table.add_record(r)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_op(&mut self, query: &SetQuery) -> DBResult<(Schema, ResultSet)> {
+        if query.op == SetOp::Product {
+            let left = self.scan_table(&query.left, None)?;
+            let right = self.scan_table(&query.right, None)?;
+            let mut columns = self.get_table(&query.left)?.get_info().schema.columns;
+            columns.extend(self.get_table(&query.right)?.get_info().schema.columns);
+            let join = Join { table: query.right.clone(), alias: None, kind: JoinKind::Cross, on: None };
+            let res = join_step(left, right, &join)?;
+            for (c, name) in columns.iter_mut().zip(&res.columns) {
+                c.name = name.clone();
+                c.references = None;
+                c.fulltext = false;
+            }
+            let res = if query.distinct { dedup(res) } else { res };
+            return Ok((Schema { columns, ..Schema::default() }, res));
+        }
+
+        let (lschema, lrecs) = {
+            let t = self.get_table(&query.left)?;
+            (t.get_info().schema, t.get_records())
+        };
+        let (rschema, rrecs) = {
+            let t = self.get_table(&query.right)?;
+            (t.get_info().schema, t.get_records())
+        };
+        if lschema.columns.len() != rschema.columns.len() {
+            return Err(TypeMismatch);
+        }
+        let columns = lschema.columns.iter().zip(&rschema.columns)
+            .map(|(l, r)| -> DBResult<Column> {
+                Ok(Column {
+                    name: l.name.clone(),
+                    ctype: l.ctype.unify(&r.ctype).ok_or(TypeMismatch)?,
+                    nullable: l.nullable || r.nullable,
+                    references: None,
+                    fulltext: false,
+                })
+            })
+            .collect::<DBResult<Vec<_>>>()?;
+        let coerce = |recs: Vec<Record>| recs.into_iter()
+            .map(|r| r.value.iter().zip(&columns)
+                .map(|(v, c)| v.coerce(&c.ctype).ok_or(TypeMismatch))
+                .collect::<DBResult<Vec<_>>>())
+            .collect::<DBResult<Vec<_>>>();
+        let (lrows, rrows) = (coerce(lrecs)?, coerce(rrecs)?);
+
+        let mut counts: HashMap<Vec<u8>, usize> = HashMap::new();
+        for r in &rrows {
+            *counts.entry(hash_key_row(r)).or_insert(0) += 1;
+        }
+        let mut records = vec![];
+        match query.op {
+            SetOp::Union => {
+                records.extend(lrows);
+                records.extend(rrows);
+            },
+            SetOp::Intersect | SetOp::Difference => {
+                let keep = query.op == SetOp::Intersect;
+                for r in lrows {
+                    let found = match counts.entry(hash_key_row(&r)) {
+                        Entry::Occupied(mut e) => {
+                            *e.get_mut() -= 1;
+                            if *e.get() == 0 {
+                                e.remove();
+                            }
+                            true
+                        },
+                        Entry::Vacant(_) => false
+                    };
+                    if found == keep {
+                        records.push(r);
+                    }
+                }
+            },
+            SetOp::Product => unreachable!()
+        }
+        let res = ResultSet {
+            columns: columns.iter().map(|c| c.name.clone()).collect(),
+            records
+        };
+        let res = if query.distinct { dedup(res) } else { res };
+        Ok((Schema { columns, ..Schema::default() }, res))
+    }
+
+    fn columns_of(&mut self, name: &str, prefix: Option<&str>) -> DBResult<Vec<Column>> {
+        let mut columns = self.get_table(name)?.get_info().schema.columns;
+        for c in &mut columns {
+            if let Some(p) = prefix {
+                c.name = format!("{}.{}", p, c.name);
+            }
+            c.references = None;
+            c.fulltext = false;
+        }
+        Ok(columns)
+    }
+
+    pub fn run_query(&mut self, query: &Query) -> DBResult<(Schema, ResultSet)> {
+        let (mut columns, mut res) = if query.joins.is_empty() {
+            let columns = self.columns_of(&query.from, None)?;
+            let table = self.get_table(&query.from)?;
+            (columns, ResultSet::from_records(&table.get_info().schema, table.get_records()))
+        } else {
+            let prefix = query.alias.as_ref().map(String::as_str).unwrap_or(&query.from);
+            let mut columns = self.columns_of(&query.from, Some(prefix))?;
+            for j in &query.joins {
+                let prefix = j.alias.as_ref().map(String::as_str).unwrap_or(&j.table);
+                let mut jc = self.columns_of(&j.table, Some(prefix))?;
+                if j.kind == JoinKind::Left {
+                    for c in &mut jc {
+                        c.nullable = true;
+                    }
+                }
+                columns.extend(jc);
+            }
+            let join = JoinQuery {
+                table: query.from.clone(),
+                alias: query.alias.clone(),
+                joins: query.joins.clone()
+            };
+            (columns, self.join(&join)?)
+        };
+        if let Some(filter) = &query.filter {
+            let mut records = vec![];
+            for r in res.records {
+                if is_true(&filter.eval(&res.columns, &r)?) {
+                    records.push(r);
+                }
+            }
+            res.records = records;
+        }
+        if let Some(select) = &query.select {
+            columns = select.iter()
+                .map(|p| match &p.expr {
+                    Expr::Column(c) => {
+                        let mut col = columns.iter().find(|x| x.name == *c).cloned()
+                            .unwrap_or_else(|| Column::new(c, Type::Str));
+                        col.name = p.name();
+                        col
+                    },
+                    e => Column { nullable: true, ..Column::new(&p.name(), e.infer_type(&columns)) }
+                })
+                .collect();
+            res = project(&res, select)?;
+        }
+        Ok((Schema { columns, ..Schema::default() }, res))
+    }
+
+    pub fn join(&mut self, query: &JoinQuery) -> DBResult<ResultSet> {
+        let mut res = self.scan_table(&query.table, query.alias.as_ref().map(String::as_str))?;
+        for join in &query.joins {
+            let right = self.scan_table(&join.table, join.alias.as_ref().map(String::as_str))?;
+            res = join_step(res, right, join)?;
+        }
+        Ok(res)
+    }
+}
+
+impl Query {
+    pub fn sources(&self) -> Vec<&str> {
+        let mut sources = vec![self.from.as_str()];
+        sources.extend(self.joins.iter().map(|j| j.table.as_str()));
+        sources
+    }
+
+    pub fn retarget(&mut self, old: &str, new: &str) {
+        if self.from == old {
+            self.alias = self.alias.take().or_else(|| Some(old.to_string()));
+            self.from = new.to_string();
+        }
+        for j in &mut self.joins {
+            if j.table == old {
+                j.alias = j.alias.take().or_else(|| Some(old.to_string()));
+                j.table = new.to_string();
+            }
+        }
+    }
+}
+
+pub struct View {
+    name: String,
+    schema: Schema,
+    records: Vec<Record>,
+}
+
+impl View {
+    pub fn new(name: &str, schema: Schema, res: ResultSet) -> View {
+        View {
+            name: name.to_string(),
+            schema,
+            records: res.records.into_iter()
+                .enumerate()
+                .map(|(i, value)| Record { ident: i as u64, value })
+                .collect()
+        }
+    }
+}
+
+impl ITable for View {
+    fn get_info(&self) -> TableInfo {
+        TableInfo {
+            name: self.name.clone(),
+            schema: self.schema.clone()
+        }
+    }
+
+    fn add_record(&mut self, _value: &[DBValue]) -> DBResult<u64> {
+        Err(ReadOnlyView)
+    }
+
+    fn upd_record(&mut self, _ident: u64, _value: &[DBValue]) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn del_record(&mut self, _ident: u64) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn del_record_by_idx(&mut self, _idx: u64) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn upd_record_by_idx(&mut self, _idx: u64, _value: &[DBValue]) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn sort_records(&self, keys: &[SortKey], collation: Collation) -> DBResult<Vec<Record>> {
+        sort_by_keys(&self.schema, self.get_records(), keys, collation)
+    }
+
+    fn get_records(&self) -> Vec<Record> {
+        self.records.clone()
+    }
+
+    fn select(&self, projection: &[Projection]) -> DBResult<ResultSet> {
+        project(&ResultSet::from_records(&self.schema, self.get_records()), projection)
+    }
+
+    fn aggregate(&self, query: &AggQuery) -> DBResult<ResultSet> {
+        aggregate(&ResultSet::from_records(&self.schema, self.get_records()), query)
+    }
+
+    fn search(&self, query: &str) -> Vec<SearchHit> {
+        let terms = fts::tokenize(query);
+        self.records.iter()
+            .filter(|r| {
+                let tokens: HashSet<String> = r.value.iter()
+                    .filter_map(DBValue::as_text)
+                    .flat_map(|t| fts::tokenize(&t))
+                    .collect();
+                !terms.is_empty() && terms.iter().all(|t| tokens.contains(t))
+            })
+            .map(|r| SearchHit { record: r.clone(), score: 1.0 })
+            .collect()
+    }
+
+    fn add_column(&mut self, _column: &Column, _idx: Option<usize>) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn del_column(&mut self, _column: String) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn move_column(&mut self, _column: String, _idx: usize) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn upd_column(&mut self, _old: String, _new: &Column) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn upd_column_with(&mut self, _old: String, _new: &Column, _policy: FailurePolicy, _progress: &dyn Fn(usize, usize)) -> DBResult<ColumnReport> {
+        Err(ReadOnlyView)
+    }
+
+    fn column_failures(&self, old: &str, new: &Column) -> DBResult<Vec<RowFailure>> {
+        let idx = self.schema.columns.iter().position(|c| c.name == old).ok_or(InvalidColumn)?;
+        Ok(self.get_records().into_iter()
+            .filter(|r| new.convert(&r.value[idx]).is_none())
+            .map(|r| RowFailure { ident: r.ident, value: r.value[idx].clone() })
+            .collect())
+    }
+
+    fn add_trigger(&mut self, _trigger: &Trigger) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+
+    fn del_trigger(&mut self, _name: &str) -> DBResult<()> {
+        Err(ReadOnlyView)
+    }
+}
+
+fn hash_key(value: &DBValue) -> Vec<u8> {
+    serialize(value).unwrap()
+}
+
+fn hash_key_row(row: &[DBValue]) -> Vec<u8> {
+    serialize(row).unwrap()
+}
+
+fn dedup(res: ResultSet) -> ResultSet {
+    let mut seen = HashSet::new();
+    let records = res.records.into_iter()
+        .filter(|r| seen.insert(hash_key_row(r)))
+        .collect();
+    ResultSet { columns: res.columns, records }
+}
+
+fn concat(l: &[DBValue], r: &[DBValue]) -> Vec<DBValue> {
+    l.iter().chain(r).cloned().collect()
+}
+
+fn join_step(left: ResultSet, right: ResultSet, join: &Join) -> DBResult<ResultSet> {
+    let mut records = vec![];
+    match (join.kind, &join.on) {
+        (JoinKind::Cross, _) => {
+            for l in &left.records {
+                for r in &right.records {
+                    records.push(concat(l, r));
+                }
+            }
+        },
+        (kind, Some(on)) => {
+            let li = left.column(&on.left)?;
+            let ri = right.column(&on.right)?;
+            let mut index: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+            for (i, r) in right.records.iter().enumerate() {
+                if r[ri] != DBValue::Null {
+                    index.entry(hash_key(&r[ri])).or_insert_with(Vec::new).push(i);
+                }
+            }
+            let pad = vec![DBValue::Null; right.columns.len()];
+            for l in &left.records {
+                let hits = if l[li] == DBValue::Null { None } else { index.get(&hash_key(&l[li])) };
+                match hits {
+                    Some(hits) => for &i in hits {
+                        records.push(concat(l, &right.records[i]));
+                    },
+                    None if kind == JoinKind::Left => records.push(concat(l, &pad)),
+                    None => ()
+                }
+            }
+        },
+        (_, None) => return Err(InvalidQuery)
+    }
+    let mut columns = left.columns;
+    columns.extend(right.columns);
+    Ok(ResultSet { columns, records })
+}
+
+impl Aggregate {
+    pub fn name(&self) -> String {
+        if let Some(a) = &self.alias {
+            return a.clone();
+        }
+        let func = match self.func {
+            AggFunc::Count | AggFunc::CountDistinct => "count",
+            AggFunc::Sum => "sum",
+            AggFunc::Avg => "avg",
+            AggFunc::Min => "min",
+            AggFunc::Max => "max",
+        };
+        match (&self.column, self.func) {
+            (None, _) => format!("{}(*)", func),
+            (Some(c), AggFunc::CountDistinct) => format!("{}(distinct {})", func, c),
+            (Some(c), _) => format!("{}({})", func, c),
+        }
+    }
+}
+
+fn aggregate_call(f: &str, args: &[Expr], alias: Option<String>) -> DBResult<Aggregate> {
+    let func = match f {
+        "count" => AggFunc::Count,
+        "count_distinct" => AggFunc::CountDistinct,
+        "sum" => AggFunc::Sum,
+        "avg" => AggFunc::Avg,
+        "min" => AggFunc::Min,
+        "max" => AggFunc::Max,
+        _ => return Err(InvalidExpression)
+    };
+    let column = match (args, func) {
+        ([], AggFunc::Count) => None,
+        ([Expr::Column(c)], _) => Some(c.clone()),
+        _ => return Err(InvalidExpression)
+    };
+    Ok(Aggregate { func, column, alias })
+}
+
+pub fn parse_aggregates(s: &str) -> DBResult<Vec<Aggregate>> {
+    parse_projection(s)?.into_iter()
+        .map(|p| match p.expr {
+            Expr::Call(f, args) => aggregate_call(&f, &args, p.alias),
+            _ => Err(InvalidExpression)
+        })
+        .collect()
+}
+
+// Replaces aggregate calls in a HAVING expression with references to the
+// matching output column, adding hidden aggregates for calls that are not
+// selected.
+fn resolve_having(expr: &Expr, aggregates: &mut Vec<Aggregate>) -> Expr {
+    match expr {
+        Expr::Call(f, args) => match aggregate_call(f, args, None) {
+            Ok(a) => {
+                let idx = match aggregates.iter().position(|x| x.func == a.func && x.column == a.column) {
+                    Some(idx) => idx,
+                    None => {
+                        aggregates.push(a);
+                        aggregates.len() - 1
+                    }
+                };
+                Expr::Column(aggregates[idx].name())
+            },
+            Err(_) => Expr::Call(f.clone(), args.iter().map(|a| resolve_having(a, aggregates)).collect())
+        },
+        Expr::Unary(op, e) => Expr::Unary(*op, Box::new(resolve_having(e, aggregates))),
+        Expr::Binary(op, l, r) => Expr::Binary(*op, Box::new(resolve_having(l, aggregates)), Box::new(resolve_having(r, aggregates))),
+        Expr::Cast(e, t) => Expr::Cast(Box::new(resolve_having(e, aggregates)), t.clone()),
+        e => e.clone()
+    }
+}
+
+enum Acc {
+    Count(i64),
+    Distinct(HashSet<Vec<u8>>),
+    Sum(DBValue),
+    Avg(f64, i64),
+    Min(DBValue),
+    Max(DBValue),
+}
+
+impl Acc {
+    fn new(func: AggFunc) -> Acc {
+        match func {
+            AggFunc::Count => Acc::Count(0),
+            AggFunc::CountDistinct => Acc::Distinct(HashSet::new()),
+            AggFunc::Sum => Acc::Sum(DBValue::Null),
+            AggFunc::Avg => Acc::Avg(0.0, 0),
+            AggFunc::Min => Acc::Min(DBValue::Null),
+            AggFunc::Max => Acc::Max(DBValue::Null),
+        }
+    }
+
+    fn add(&mut self, v: &DBValue) -> DBResult<()> {
+        if *v == DBValue::Null {
+            return Ok(());
+        }
+        match self {
+            Acc::Count(n) => *n += 1,
+            Acc::Distinct(set) => { set.insert(hash_key(v)); },
+            Acc::Sum(s) => {
+                *s = match (&*s, v) {
+                    (DBValue::Null, DBValue::Integer(_)) | (DBValue::Null, DBValue::Real(_)) => v.clone(),
+                    (DBValue::Integer(a), DBValue::Integer(b)) => DBValue::Integer(a.checked_add(*b).ok_or(Overflow)?),
+                    (DBValue::Integer(a), DBValue::Real(b)) => DBValue::Real(*a as f64 + b),
+                    (DBValue::Real(a), DBValue::Integer(b)) => DBValue::Real(a + *b as f64),
+                    (DBValue::Real(a), DBValue::Real(b)) => DBValue::Real(a + b),
+                    _ => return Err(TypeMismatch)
+                };
+            },
+            Acc::Avg(s, n) => {
+                *s += match v {
+                    DBValue::Integer(i) => *i as f64,
+                    DBValue::Real(f) => *f,
+                    _ => return Err(TypeMismatch)
+                };
+                *n += 1;
+            },
+            Acc::Min(m) => if *m == DBValue::Null || v.total_cmp(m, Collation::Byte) == Ordering::Less {
+                *m = v.clone();
+            },
+            Acc::Max(m) => if *m == DBValue::Null || v.total_cmp(m, Collation::Byte) == Ordering::Greater {
+                *m = v.clone();
+            },
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> DBValue {
+        match self {
+            Acc::Count(n) => DBValue::Integer(n),
+            Acc::Distinct(set) => DBValue::Integer(set.len() as i64),
+            Acc::Avg(_, 0) => DBValue::Null,
+            Acc::Avg(s, n) => DBValue::Real(s / n as f64),
+            Acc::Sum(v) | Acc::Min(v) | Acc::Max(v) => v,
+        }
+    }
+}
+
+pub fn aggregate(res: &ResultSet, query: &AggQuery) -> DBResult<ResultSet> {
+    let mut aggregates = query.aggregates.clone();
+    let having = query.having.as_ref().map(|h| resolve_having(h, &mut aggregates));
+    let keys = query.group_by.iter()
+        .map(|c| res.column(c))
+        .collect::<DBResult<Vec<_>>>()?;
+    let cols = aggregates.iter()
+        .map(|a| match &a.column {
+            Some(c) => res.column(c).map(Some),
+            None => Ok(None)
+        })
+        .collect::<DBResult<Vec<_>>>()?;
+
+    let mut index: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut groups: Vec<(Vec<DBValue>, Vec<Acc>)> = vec![];
+    if keys.is_empty() {
+        groups.push((vec![], aggregates.iter().map(|a| Acc::new(a.func)).collect()));
+        index.insert(hash_key_row(&[]), 0);
+    }
+    for row in &res.records {
+        let key: Vec<DBValue> = keys.iter().map(|&k| row[k].clone()).collect();
+        let g = *index.entry(hash_key_row(&key)).or_insert_with(|| {
+            groups.push((key, aggregates.iter().map(|a| Acc::new(a.func)).collect()));
+            groups.len() - 1
+        });
+        for (acc, col) in groups[g].1.iter_mut().zip(&cols) {
+            match col {
+                Some(c) => acc.add(&row[*c])?,
+                None => acc.add(&DBValue::Integer(1))?,
+            }
+        }
+    }
+
+    let mut columns = query.group_by.clone();
+    columns.extend(aggregates.iter().map(Aggregate::name));
+    let width = query.group_by.len() + query.aggregates.len();
+    let mut records = vec![];
+    for (mut key, accs) in groups {
+        key.extend(accs.into_iter().map(Acc::finish));
+        let keep = match &having {
+            Some(h) => h.eval(&columns, &key)? == DBValue::Integer(1),
+            None => true
+        };
+        if keep {
+            key.truncate(width);
+            records.push(key);
+        }
+    }
+    columns.truncate(width);
+    Ok(ResultSet { columns, records })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sales() -> ResultSet {
+        let row = |g: &str, qty: i64| vec![DBValue::Str(g.to_string()), DBValue::Integer(qty)];
+        ResultSet {
+            columns: vec!["g".to_string(), "qty".to_string()],
+            records: vec![row("a", 4), row("a", 9), row("b", 3), row("c", 20)],
+        }
+    }
+
+    fn query(agg: &str, having: &str) -> AggQuery {
+        AggQuery {
+            group_by: vec!["g".to_string()],
+            aggregates: parse_aggregates(agg).unwrap(),
+            having: Some(Expr::parse(having).unwrap()),
+        }
+    }
+
+    #[test]
+    fn having_resolves_selected_aggregates() {
+        let res = aggregate(&sales(), &query("sum(qty)", "sum(qty) > 10")).unwrap();
+        assert_eq!(res.columns, vec!["g".to_string(), "sum(qty)".to_string()]);
+        assert_eq!(res.records, vec![
+            vec![DBValue::Str("a".to_string()), DBValue::Integer(13)],
+            vec![DBValue::Str("c".to_string()), DBValue::Integer(20)],
+        ]);
+    }
+
+    #[test]
+    fn having_resolves_aliased_and_hidden_aggregates() {
+        let res = aggregate(&sales(), &query("sum(qty) as total", "count(*) >= 2 and sum(qty) < 100")).unwrap();
+        assert_eq!(res.columns, vec!["g".to_string(), "total".to_string()]);
+        assert_eq!(res.records, vec![vec![DBValue::Str("a".to_string()), DBValue::Integer(13)]]);
+    }
+}
@@ -1,12 +1,32 @@
 #![allow(clippy::needless_pass_by_value)]
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::Duration;
+
 use rocket_contrib::{json::{Json, JsonValue}};
-use rocket::{Route, response::Responder};
+use rocket::{Data, Route, response::Responder};
+use rocket::http::ContentType;
+use rocket::response::{Stream, content::Content};
 use problem::{Problem, ToProblem};
 
+use crate::backup::{self, Archive};
+use crate::catalog;
+use crate::changes::{self, EventStream};
 use crate::db::*;
+use crate::export::{self, Export};
+use crate::expr::*;
+use crate::getset::GetSet;
+use crate::integrity::{CheckReport, RepairMode};
+use crate::matview::{self, MatView};
+use crate::migrate::SchemaOp;
+use crate::query::*;
+use crate::trigger::Trigger;
+use crate::vacuum::VacuumReport;
 
 lazy_static! {
-    pub static ref ROUTES: Vec<Route> = routes![getdbs, opendb, gettables, addtable, gettable, deltable, addrecord, getrecords, delrecord, updrecord, sortrecords, addcolumn, delcolumn, movecolumn, updcolumn];
+    pub static ref ROUTES: Vec<Route> = routes![getdbs, opendb, closedb, dropdb, renamedb, gettables, addtable, gettable, tablestats, setidgen, deltable, renametable, copytable, addrecord, getrecords, delrecord, updrecord, sortrecords, addcolumn, delcolumn, movecolumn, updcolumn, join, setop, aggregaterecords, searchrecords, addview, delview, addmatview, getmatview, refreshmatview, addtrigger, deltrigger, getchanges, streamchanges, getmigrations, migratetable, revertschema, columnprogress, vacuumdb, checkdb, backupdb, restoredb, restorebackup, exportdb, exporttable];
+    static ref PROGRESS: Mutex<BTreeMap<String, (usize, usize)>> = Mutex::new(BTreeMap::new());
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,12 +48,12 @@ struct TablesReq {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct DbList {
-    databases: Vec<String>
+    databases: Vec<catalog::Listing>
 }
 
 #[get("/")]
 fn getdbs() -> Json<DbList> {
-    Json(DbList {databases: get_dbs()})
+    Json(DbList {databases: catalog::list(&*DATABASES.lock().unwrap())})
 }
 
 #[get("/<id>/open")]
@@ -42,20 +62,132 @@ fn opendb(id: String) -> DBResult<JsonValue> {
     Ok(json!({"handle": &id}))
 }
 
+#[post("/<id>/close")]
+fn closedb(id: String) -> DBResult<JsonValue> {
+    catalog::close(&mut *DATABASES.lock().unwrap(), &id)?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[delete("/<id>?<confirm>")]
+fn dropdb(id: String, confirm: Option<String>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    match confirm {
+        Some(token) => {
+            catalog::drop(&mut *dbs, &id, &token)?;
+            Ok(json!({"status": "ok"}))
+        },
+        None => Ok(json!({"status": "confirm", "confirm": catalog::drop_token(&*dbs, &id)?}))
+    }
+}
+
+#[post("/<id>/rename", data="<data>")]
+fn renamedb(id: String, data: Json<OpenReq>) -> DBResult<JsonValue> {
+    catalog::rename(&mut *DATABASES.lock().unwrap(), &id, &data.name)?;
+    Ok(json!({"handle": &data.name}))
+}
+
+
+#[post("/<id>/vacuum?<dry_run>")]
+fn vacuumdb(id: String, dry_run: Option<bool>) -> DBResult<Json<VacuumReport>> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let report = if dry_run.unwrap_or(false) {
+        db.dry_run(|db| db.vacuum())?
+    } else {
+        db.atomic(|db| db.vacuum())?
+    };
+    Ok(Json(report))
+}
+
+#[post("/<id>/check?<repair>")]
+fn checkdb(id: String, repair: Option<String>) -> DBResult<Json<CheckReport>> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let report = match repair {
+        Some(mode) => {
+            let mode = RepairMode::parse(&mode)?;
+            db.atomic(|db| db.repair(mode))?
+        },
+        None => CheckReport { issues: db.check()?, repaired: 0 }
+    };
+    Ok(Json(report))
+}
+
+#[post("/<id>/backup?<name>")]
+fn backupdb(id: String, name: Option<String>) -> DBResult<Content<Vec<u8>>> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let archive = db.backup(&id)?;
+    match name {
+        Some(name) => {
+            let path = archive.save(&name)?;
+            Ok(Content(ContentType::JSON, json!({"path": path, "seq": archive.seq}).to_string().into_bytes()))
+        },
+        None => Ok(Content(ContentType::new("application", "x-ndjson"), archive.encode()))
+    }
+}
+
+#[post("/<id>/restore", data="<data>")]
+fn restoredb(id: String, data: Data) -> DBResult<JsonValue> {
+    let mut body = vec![];
+    data.open().take(backup::RESTORE_LIMIT + 1).read_to_end(&mut body).map_err(|_| DBError::StoreError)?;
+    if body.len() as u64 > backup::RESTORE_LIMIT {
+        return Err(DBError::ArchiveTooLarge);
+    }
+    let archive = Archive::decode(&body)?;
+    backup::restore_new(&mut *DATABASES.lock().unwrap(), &id, &archive)?;
+    Ok(json!({"handle": &id}))
+}
+
+#[post("/<id>/restore/<name>")]
+fn restorebackup(id: String, name: String) -> DBResult<JsonValue> {
+    let archive = Archive::load(&name)?;
+    backup::restore_new(&mut *DATABASES.lock().unwrap(), &id, &archive)?;
+    Ok(json!({"handle": &id}))
+}
+
+fn export_stream(id: &str, tables: Vec<String>, format: Option<String>, whole: bool) -> DBResult<Content<Stream<Export>>> {
+    let format = export::Format::parse(format.as_ref().map_or("jsonl", String::as_str))?;
+    let (top, sub) = format.content_type();
+    let stream = Export::new(id, tables, format, whole)?;
+    Ok(Content(ContentType::new(top, sub), Stream::chunked(stream, 4096)))
+}
+
+#[get("/<id>/export?<format>")]
+fn exportdb(id: String, format: Option<String>) -> DBResult<Content<Stream<Export>>> {
+    let tables = {
+        let mut dbs = DATABASES.lock().unwrap();
+        get_db(&mut *dbs, &id)?.get_tables()?
+    };
+    export_stream(&id, tables, format, true)
+}
+
+#[get("/<id>/table/<name>/export?<format>")]
+fn exporttable(id: String, name: String, format: Option<String>) -> DBResult<Content<Stream<Export>>> {
+    {
+        let mut dbs = DATABASES.lock().unwrap();
+        if !get_db(&mut *dbs, &id)?.get_tables()?.contains(&name) {
+            return Err(DBError::TableNotFound);
+        }
+    }
+    export_stream(&id, vec![name], format, false)
+}
 
 #[get("/<id>/tables")]
 fn gettables(id: String) -> DBResult<JsonValue> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    Ok(json!({"tables": db.get_tables()?}))
+    let objects = db.list_tables()?;
+    let names: Vec<&String> = objects.iter().map(|t| &t.name).collect();
+    Ok(json!({"tables": names, "objects": objects}))
 }
 
 #[post("/<id>/table/<name>", data="<data>")]
 fn addtable(id: String, name: String, data: Json<AddTableReq>) -> DBResult<JsonValue> {
-    let schema = data.schema.clone().unwrap_or_else(|| Schema {columns: vec![Column {name: "identifier".to_string(), ctype: Type::Integer}]});
+    let schema = data.schema.clone().unwrap_or_else(|| Schema {columns: vec![Column::new("identifier", Type::Integer)], ..Schema::default()});
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    db.add_table(&name, &schema)?;
+    db.atomic(|db| db.add_table(&name, &schema))?;
     Ok(json!({"status": "ok"}))
 }
 
@@ -69,11 +201,146 @@ fn gettable(id: String, name: String) -> DBResult<Json<TableInfo>> {
     Ok(Json(table.get_info()))
 }
 
-#[delete("/<id>/table/<name>")]
-fn deltable(id: String, name: String) -> DBResult<JsonValue> {
+#[get("/<id>/table/<name>/stats")]
+fn tablestats(id: String, name: String) -> DBResult<Json<TableStats>> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    Ok(Json(db.table_stats(&name)?))
+}
+
+#[put("/<id>/table/<name>/ids/<ids>")]
+fn setidgen(id: String, name: String, ids: String) -> DBResult<JsonValue> {
+    let ids = IdGen::parse(&ids)?;
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.set_id_generator(&name, ids))?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[delete("/<id>/table/<name>?<cascade>")]
+fn deltable(id: String, name: String, cascade: Option<bool>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.remove_table(&name, cascade.unwrap_or(false)))?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[post("/<id>/table/<name>/rename", data="<data>")]
+fn renametable(id: String, name: String, data: Json<OpenReq>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.rename_table(&name, &data.name))?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CopyReq {
+    name: String,
+    #[serde(default)]
+    data: bool,
+    filter: Option<String>,
+}
+
+#[post("/<id>/table/<name>/copy", data="<data>")]
+fn copytable(id: String, name: String, data: Json<CopyReq>) -> DBResult<JsonValue> {
+    let filter = match &data.filter {
+        Some(f) => Some(Expr::parse(f)?),
+        None => None
+    };
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let rows = db.atomic(|db| db.copy_table(&name, &data.name, data.data, filter.as_ref()))?;
+    Ok(json!({"status": "ok", "rows": rows}))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ViewReq {
+    from: String,
+    alias: Option<String>,
+    #[serde(default)]
+    joins: Vec<Join>,
+    filter: Option<String>,
+    select: Option<String>,
+}
+
+impl ViewReq {
+    fn query(self) -> DBResult<Query> {
+        Ok(Query {
+            from: self.from,
+            alias: self.alias,
+            joins: self.joins,
+            filter: match self.filter {
+                Some(f) => Some(Expr::parse(&f)?),
+                None => None
+            },
+            select: match self.select {
+                Some(s) => Some(parse_projection(&s)?),
+                None => None
+            },
+        })
+    }
+}
+
+#[post("/<id>/view/<name>", data="<data>")]
+fn addview(id: String, name: String, data: Json<ViewReq>) -> DBResult<JsonValue> {
+    let query = data.into_inner().query()?;
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.add_view(&name, &query))?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[delete("/<id>/view/<name>?<cascade>")]
+fn delview(id: String, name: String, cascade: Option<bool>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.remove_view(&name, cascade.unwrap_or(false)))?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MatViewReq {
+    #[serde(flatten)]
+    query: ViewReq,
+    group_by: Option<String>,
+    agg: Option<String>,
+    having: Option<String>,
+}
+
+#[post("/<id>/matview/<name>", data="<data>")]
+fn addmatview(id: String, name: String, data: Json<MatViewReq>) -> DBResult<JsonValue> {
+    let data = data.into_inner();
+    let aggregate = match data.agg {
+        Some(agg) => Some(AggQuery {
+            group_by: data.group_by.map(|g| g.split(',').map(|c| c.trim().to_string()).collect()).unwrap_or_default(),
+            aggregates: parse_aggregates(&agg)?,
+            having: match data.having {
+                Some(h) => Some(Expr::parse(&h)?),
+                None => None
+            },
+        }),
+        None => None
+    };
+    let def = MatView { query: data.query.query()?, aggregate };
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.add_matview(&name, &def))?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[get("/<id>/matview/<name>")]
+fn getmatview(id: String, name: String) -> DBResult<JsonValue> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    db.remove_table(&name)?;
+    let (def, stale) = matview::status(&db.tree, &name)?;
+    Ok(json!({"definition": def, "stale": stale}))
+}
+
+#[post("/<id>/matview/<name>/refresh")]
+fn refreshmatview(id: String, name: String) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.refresh_matview(&name))?;
     Ok(json!({"status": "ok"}))
 }
 
@@ -103,28 +370,84 @@ struct NewRecord {
     id: u64
 }
 
-#[get("/<id>/table/<name>/records")]
-fn getrecords(id: String, name: String) -> DBResult<Json<GetRecords>> {
+fn collation(c: Option<String>) -> DBResult<Collation> {
+    c.map_or(Ok(Collation::default()), |c| Collation::parse(&c))
+}
+
+#[get("/<id>/table/<name>/records?<select>&<sort_by>&<collation>")]
+fn getrecords(id: String, name: String, select: Option<String>, sort_by: Option<String>, collation: Option<String>) -> DBResult<JsonValue> {
+    let collation = self::collation(collation)?;
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
     let table = db.get_table(&name)?;
-    Ok(Json(GetRecords {records: table.get_records()}))
+    let records = match sort_by {
+        Some(keys) => table.sort_records(&SortKey::parse_list(&keys)?, collation)?,
+        None => table.get_records()
+    };
+    match select {
+        Some(select) => {
+            let res = ResultSet::from_records(&table.get_info().schema, records);
+            Ok(json!(project(&res, &parse_projection(&select)?)?))
+        },
+        None => Ok(json!(GetRecords {records}))
+    }
+}
+
+#[get("/<id>/table/<name>/aggregate?<group_by>&<agg>&<having>")]
+fn aggregaterecords(id: String, name: String, group_by: Option<String>, agg: String, having: Option<String>) -> DBResult<Json<ResultSet>> {
+    let query = AggQuery {
+        group_by: group_by.map(|g| g.split(',').map(|c| c.trim().to_string()).collect()).unwrap_or_default(),
+        aggregates: parse_aggregates(&agg)?,
+        having: match having {
+            Some(h) => Some(Expr::parse(&h)?),
+            None => None
+        },
+    };
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let table = db.get_table(&name)?;
+    Ok(Json(table.aggregate(&query)?))
+}
+
+#[get("/<id>/table/<name>/records/search?<q>")]
+fn searchrecords(id: String, name: String, q: String) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let table = db.get_table(&name)?;
+    Ok(json!({"hits": table.search(&q)}))
+}
+
+#[get("/<id>/table/<name>/changes?<since>&<timeout>")]
+fn getchanges(id: String, name: String, since: Option<u64>, timeout: Option<u64>) -> DBResult<JsonValue> {
+    let since = since.unwrap_or(0);
+    let changes = changes::poll(&id, &name, since, Duration::from_secs(timeout.unwrap_or(30).min(60)))?;
+    let last = changes.last().map_or(since, |c| c.seq);
+    Ok(json!({"changes": changes, "last": last}))
+}
+
+#[get("/<id>/table/<name>/changes/stream?<since>")]
+fn streamchanges(id: String, name: String, since: Option<u64>) -> DBResult<Content<Stream<EventStream>>> {
+    {
+        let mut dbs = DATABASES.lock().unwrap();
+        get_db(&mut *dbs, &id)?.changes(&name, std::u64::MAX)?;
+    }
+    let stream = EventStream::new(&id, &name, since.unwrap_or(0));
+    Ok(Content(ContentType::new("text", "event-stream"), Stream::chunked(stream, changes::CHUNK)))
 }
 
 #[post("/<id>/table/<name>/record", data="<data>")]
 fn addrecord(id: String, name: String, data: Json<RecordPrint>) -> DBResult<Json<NewRecord>> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    let mut table = db.get_table(&name)?;
-    Ok(Json(NewRecord {id: table.add_record(&data.value.as_slice())?}))
+    let ident = db.atomic(|db| db.get_table(&name)?.add_record(&data.value))?;
+    Ok(Json(NewRecord {id: ident}))
 }
 
 #[delete("/<id>/table/<name>/record/<idx>")]
 fn delrecord(id: String, name: String, idx: u64) -> DBResult<JsonValue> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    let mut table = db.get_table(&name)?;
-    table.del_record_by_idx(idx)?;
+    db.atomic(|db| db.get_table(&name)?.del_record_by_idx(idx))?;
     Ok(json!({"status": "ok"}))
 }
 
@@ -132,17 +455,17 @@ fn delrecord(id: String, name: String, idx: u64) -> DBResult<JsonValue> {
 fn updrecord(id: String, name: String, idx: u64, data: Json<RecordPrint>) -> DBResult<JsonValue> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    let mut table = db.get_table(&name)?;
-    table.upd_record_by_idx(idx, &data.value)?;
+    db.atomic(|db| db.get_table(&name)?.upd_record_by_idx(idx, &data.value))?;
     Ok(json!({"status": "ok"}))
 }
 
-#[get("/<id>/table/<name>/records/sort_by/<column>")]
-fn sortrecords(id: String, name: String, column: String) -> DBResult<Json<GetRecords>> {
+#[get("/<id>/table/<name>/records/sort_by/<column>?<collation>")]
+fn sortrecords(id: String, name: String, column: String, collation: Option<String>) -> DBResult<Json<GetRecords>> {
+    let collation = self::collation(collation)?;
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
     let table = db.get_table(&name)?;
-    Ok(Json(GetRecords {records: table.sort_records(column)?}))
+    Ok(Json(GetRecords {records: table.sort_records(&SortKey::parse_list(&column)?, collation)?}))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -156,8 +479,7 @@ struct ColumnReq {
 fn addcolumn(id: String, name: String, data: Json<ColumnReq>) -> DBResult<JsonValue> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    let mut table = db.get_table(&name)?;
-    table.add_column(&data.column, data.index)?;
+    db.atomic(|db| db.get_table(&name)?.add_column(&data.column, data.index))?;
     Ok(json!({"status": "ok"}))
 }
 
@@ -165,8 +487,7 @@ fn addcolumn(id: String, name: String, data: Json<ColumnReq>) -> DBResult<JsonVa
 fn delcolumn(id: String, name: String, cname: String) -> DBResult<JsonValue> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    let mut table = db.get_table(&name)?;
-    table.del_column(cname)?;
+    db.atomic(|db| db.get_table(&name)?.del_column(cname))?;
     Ok(json!({"status": "ok"}))
 }
 
@@ -179,8 +500,7 @@ struct MoveReq {
 fn movecolumn(id: String, name: String, cname: String, data: Json<MoveReq>) -> DBResult<JsonValue> {
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    let mut table = db.get_table(&name)?;
-    table.move_column(cname, data.index)?;
+    db.atomic(|db| db.get_table(&name)?.move_column(cname, data.index))?;
     Ok(json!({"status": "ok"}))
 }
 
@@ -189,11 +509,114 @@ struct UpdColumnReq {
     column: Column
 }
 
-#[put("/<id>/table/<name>/column/<cname>", data="<data>")]
-fn updcolumn(id: String, name: String, cname: String, data: Json<UpdColumnReq>) -> DBResult<JsonValue> {
+#[put("/<id>/table/<name>/column/<cname>?<policy>&<dry_run>", data="<data>")]
+fn updcolumn(id: String, name: String, cname: String, policy: Option<String>, dry_run: Option<bool>, data: Json<UpdColumnReq>) -> DBResult<JsonValue> {
+    let policy = policy.map_or(Ok(FailurePolicy::default()), |p| FailurePolicy::parse(&p))?;
     let mut dbs = DATABASES.lock().unwrap();
     let db = get_db(&mut *dbs, &id)?;
-    let mut table = db.get_table(&name)?;
-    table.upd_column(cname, &data.column)?;
+    if dry_run.unwrap_or(false) {
+        let failures = db.get_table(&name)?.column_failures(&cname, &data.column)?;
+        return Ok(json!({"dry_run": true, "policy": policy, "failures": failures}));
+    }
+    let key = format!("{}/{}/{}", id, name, cname);
+    let progress = |done: usize, total: usize| {
+        PROGRESS.lock().unwrap().insert(key.clone(), (done, total));
+    };
+    let res = db.atomic(|db| db.get_table(&name)?.upd_column_with(cname.clone(), &data.column, policy, &progress));
+    PROGRESS.lock().unwrap().remove(&key);
+    Ok(json!(res?))
+}
+
+#[get("/<id>/table/<name>/column/<cname>/progress")]
+fn columnprogress(id: String, name: String, cname: String) -> JsonValue {
+    match PROGRESS.lock().unwrap().get(&format!("{}/{}/{}", id, name, cname)) {
+        Some((done, total)) => json!({"running": true, "done": done, "total": total}),
+        None => json!({"running": false})
+    }
+}
+
+#[get("/<id>/migrations?<table>")]
+fn getmigrations(id: String, table: Option<String>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let log: Vec<JsonValue> = db.migrations(table.as_ref().map(String::as_str)).into_iter()
+        .map(|m| json!({"seq": m.seq, "table": m.table, "version": m.version, "op": m.op}))
+        .collect();
+    Ok(json!({"migrations": log}))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MigrateReq {
+    ops: Vec<SchemaOp>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[post("/<id>/table/<name>/migrate", data="<data>")]
+fn migratetable(id: String, name: String, data: Json<MigrateReq>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    if data.dry_run {
+        db.dry_run(|db| run_migration(db, &name, &data))
+    } else {
+        db.atomic(|db| run_migration(db, &name, &data))
+    }
+}
+
+fn run_migration<KV: GetSet>(db: &mut DB<KV>, name: &str, req: &MigrateReq) -> DBResult<JsonValue> {
+    let report = db.migrate(name, &req.ops, req.dry_run)?;
+    let version = db.get_table(name)?.get_info().schema.version;
+    Ok(json!({"dry_run": req.dry_run, "version": version, "report": report}))
+}
+
+#[post("/<id>/table/<name>/revert/<version>")]
+fn revertschema(id: String, name: String, version: u64) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let version = db.atomic(|db| db.revert_schema(&name, version))?;
+    Ok(json!({"version": version}))
+}
+
+#[post("/<id>/table/<name>/trigger", data="<data>")]
+fn addtrigger(id: String, name: String, data: Json<Trigger>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.get_table(&name)?.add_trigger(&data))?;
     Ok(json!({"status": "ok"}))
 }
+
+#[delete("/<id>/table/<name>/trigger/<trigger>")]
+fn deltrigger(id: String, name: String, trigger: String) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    db.atomic(|db| db.get_table(&name)?.del_trigger(&trigger))?;
+    Ok(json!({"status": "ok"}))
+}
+
+#[post("/<id>/join", data="<data>")]
+fn join(id: String, data: Json<JoinQuery>) -> DBResult<Json<ResultSet>> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    Ok(Json(db.join(&data)?))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SetOpReq {
+    #[serde(flatten)]
+    query: SetQuery,
+    into: Option<String>,
+}
+
+#[post("/<id>/setop", data="<data>")]
+fn setop(id: String, data: Json<SetOpReq>) -> DBResult<JsonValue> {
+    let mut dbs = DATABASES.lock().unwrap();
+    let db = get_db(&mut *dbs, &id)?;
+    let (schema, res) = db.set_op(&data.query)?;
+    match &data.into {
+        Some(into) => {
+            db.atomic(|db| db.materialize(into, &schema, &res.records))?;
+            Ok(json!({"status": "ok"}))
+        },
+        None => Ok(json!(res))
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::db::*;
+use crate::db::DBError::*;
+use crate::expr::{Expr, is_true};
+use crate::getset::{EasyGet, GetSet};
+
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Before,
+    After,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Event {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Action {
+    Insert {
+        table: String,
+        values: Vec<String>,
+    },
+    Update {
+        table: String,
+        #[serde(default)]
+        filter: Option<String>,
+        set: BTreeMap<String, String>,
+    },
+    Delete {
+        table: String,
+        #[serde(default)]
+        filter: Option<String>,
+    },
+    Abort,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Trigger {
+    pub name: String,
+    pub timing: Timing,
+    pub events: Vec<Event>,
+    #[serde(default)]
+    pub when: Option<String>,
+    pub actions: Vec<Action>,
+}
+
+impl Action {
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            Action::Insert { table, .. } | Action::Update { table, .. } | Action::Delete { table, .. } => Some(table.as_str()),
+            Action::Abort => None
+        }
+    }
+
+    fn exprs(&self) -> Vec<&str> {
+        match self {
+            Action::Insert { values, .. } => values.iter().map(String::as_str).collect(),
+            Action::Update { filter, set, .. } => filter.iter().chain(set.values()).map(String::as_str).collect(),
+            Action::Delete { filter, .. } => filter.iter().map(String::as_str).collect(),
+            Action::Abort => vec![]
+        }
+    }
+}
+
+impl Trigger {
+    pub fn validate(&self) -> DBResult<()> {
+        if self.events.is_empty() {
+            return Err(InvalidQuery);
+        }
+        for e in self.when.iter().map(String::as_str).chain(self.actions.iter().flat_map(Action::exprs)) {
+            Expr::parse(e)?;
+        }
+        Ok(())
+    }
+
+    pub fn targets(&self, table: &str) -> bool {
+        self.actions.iter().any(|a| a.table() == Some(table))
+    }
+
+    pub fn retarget(&mut self, old: &str, new: &str) {
+        for a in &mut self.actions {
+            match a {
+                Action::Insert { table, .. } | Action::Update { table, .. } | Action::Delete { table, .. } if table == old => *table = new.to_string(),
+                _ => ()
+            }
+        }
+    }
+}
+
+pub fn validate(schema: &Schema) -> DBResult<()> {
+    for (i, t) in schema.triggers.iter().enumerate() {
+        if schema.triggers[..i].iter().any(|x| x.name == t.name) {
+            return Err(InvalidQuery);
+        }
+        t.validate()?;
+    }
+    Ok(())
+}
+
+pub fn dependents<KV: GetSet>(db: &KV, table: &str) -> DBResult<Vec<(String, String)>> {
+    let tables: Vec<String> = db.get_value("/").ok_or(TableNotFound)?;
+    let mut deps = vec![];
+    for t in tables.into_iter().filter(|t| t != table) {
+        let schema: Schema = db.get_value(&format!("#{}", t)).ok_or(TableNotFound)?;
+        for tr in schema.triggers.iter().filter(|tr| tr.targets(table)) {
+            deps.push((t.clone(), tr.name.clone()));
+        }
+    }
+    Ok(deps)
+}
+
+fn context(schema: &Schema, old: Option<&[DBValue]>, new: Option<&[DBValue]>) -> (Vec<String>, Vec<DBValue>) {
+    let mut columns = vec![];
+    let mut row = vec![];
+    for &(prefix, values) in &[("old", old), ("new", new)] {
+        for (i, c) in schema.columns.iter().enumerate() {
+            columns.push(format!("{}.{}", prefix, c.name));
+            row.push(values.map_or(DBValue::Null, |v| v[i].clone()));
+        }
+    }
+    (columns, row)
+}
+
+fn matches(filter: &Option<Expr>, columns: &[String], row: &[DBValue]) -> DBResult<bool> {
+    match filter {
+        Some(f) => Ok(is_true(&f.eval(columns, row)?)),
+        None => Ok(true)
+    }
+}
+
+fn parse_opt(e: &Option<String>) -> DBResult<Option<Expr>> {
+    match e {
+        Some(e) => Expr::parse(e).map(Some),
+        None => Ok(None)
+    }
+}
+
+fn run<KV: GetSet>(db: &mut KV, trigger: &Trigger, action: &Action, ctx: &(Vec<String>, Vec<DBValue>), depth: usize) -> DBResult<()> {
+    let (columns, row) = ctx;
+    match action {
+        Action::Abort => Err(TriggerAborted(trigger.name.clone())),
+        Action::Insert { table, values } => {
+            let value = values.iter()
+                .map(|v| Expr::parse(v)?.eval(columns, row))
+                .collect::<DBResult<Vec<_>>>()?;
+            let mut target = Table::load(table, &mut *db)?;
+            target.depth = depth;
+            let value = value.into_iter()
+                .zip(&target.schema.columns)
+                .map(|(v, c)| v.coerce(&c.ctype).ok_or(TypeMismatch))
+                .collect::<DBResult<Vec<_>>>()?;
+            target.add_record(&value)?;
+            Ok(())
+        },
+        Action::Update { table, filter, set } => {
+            let filter = parse_opt(filter)?;
+            let mut target = Table::load(table, &mut *db)?;
+            target.depth = depth;
+            let mut names: Vec<String> = target.schema.columns.iter().map(|c| c.name.clone()).collect();
+            names.extend(columns.iter().cloned());
+            let set = set.iter()
+                .map(|(c, e)| -> DBResult<(usize, Expr)> {
+                    let idx = target.schema.columns.iter().position(|x| x.name == *c).ok_or(InvalidColumn)?;
+                    Ok((idx, Expr::parse(e)?))
+                })
+                .collect::<DBResult<Vec<_>>>()?;
+            for Record { ident, value } in target.get_records() {
+                let mut full = value.clone();
+                full.extend(row.iter().cloned());
+                if !matches(&filter, &names, &full)? {
+                    continue;
+                }
+                let mut value = value;
+                for (idx, e) in &set {
+                    value[*idx] = e.eval(&names, &full)?
+                        .coerce(&target.schema.columns[*idx].ctype)
+                        .ok_or(TypeMismatch)?;
+                }
+                match target.upd_record(ident, &value) {
+                    Err(RecordNotFound) => (),
+                    r => r?
+                }
+            }
+            Ok(())
+        },
+        Action::Delete { table, filter } => {
+            let filter = parse_opt(filter)?;
+            let mut target = Table::load(table, &mut *db)?;
+            target.depth = depth;
+            let mut names: Vec<String> = target.schema.columns.iter().map(|c| c.name.clone()).collect();
+            names.extend(columns.iter().cloned());
+            for Record { ident, mut value } in target.get_records() {
+                value.extend(row.iter().cloned());
+                if matches(&filter, &names, &value)? {
+                    match target.del_record(ident) {
+                        Err(RecordNotFound) => (),
+                        r => r?
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn fire<KV: GetSet>(db: &mut KV, schema: &Schema, timing: Timing, event: Event, old: Option<&[DBValue]>, new: Option<&[DBValue]>, depth: usize) -> DBResult<()> {
+    let triggers: Vec<&Trigger> = schema.triggers.iter()
+        .filter(|t| t.timing == timing && t.events.contains(&event))
+        .collect();
+    if triggers.is_empty() {
+        return Ok(());
+    }
+    if depth >= MAX_DEPTH {
+        return Err(TriggerAborted(triggers[0].name.clone()));
+    }
+    let ctx = context(schema, old, new);
+    for t in triggers {
+        if !matches(&parse_opt(&t.when)?, &ctx.0, &ctx.1)? {
+            continue;
+        }
+        for a in &t.actions {
+            run(db, t, a, &ctx, depth + 1)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::getset::MemStore;
+
+    fn setup(trigger: Trigger) -> DB<MemStore> {
+        let mut db = DB::in_memory(MemStore::default());
+        let items = Schema { columns: vec![Column::new("name", Type::Str), Column::new("qty", Type::Integer)], ..Schema::default() };
+        let audit = Schema { columns: vec![Column::new("item", Type::Str)], ..Schema::default() };
+        db.add_table("audit", &audit).unwrap();
+        db.add_table("items", &Schema { triggers: vec![trigger], ..items }).unwrap();
+        db
+    }
+
+    fn trigger(timing: Timing, event: Event, when: Option<&str>, action: Action) -> Trigger {
+        Trigger { name: "t".to_string(), timing, events: vec![event], when: when.map(String::from), actions: vec![action] }
+    }
+
+    fn item(name: &str, qty: i64) -> Vec<DBValue> {
+        vec![DBValue::Str(name.to_string()), DBValue::Integer(qty)]
+    }
+
+    fn values(db: &mut DB<MemStore>, table: &str) -> Vec<Vec<DBValue>> {
+        db.get_table(table).unwrap().get_records().into_iter().map(|r| r.value).collect()
+    }
+
+    #[test]
+    fn after_insert_runs_actions() {
+        let audit = Action::Insert { table: "audit".to_string(), values: vec!["new.name".to_string()] };
+        let mut db = setup(trigger(Timing::After, Event::Insert, Some("new.qty > 1"), audit));
+        db.atomic(|db| db.get_table("items")?.add_record(&item("a", 1))).unwrap();
+        db.atomic(|db| db.get_table("items")?.add_record(&item("b", 2))).unwrap();
+        assert_eq!(values(&mut db, "audit"), vec![vec![DBValue::Str("b".to_string())]]);
+    }
+
+    #[test]
+    fn abort_rejects_row() {
+        let mut db = setup(trigger(Timing::Before, Event::Insert, Some("new.qty < 0"), Action::Abort));
+        match db.atomic(|db| db.get_table("items")?.add_record(&item("a", -1))) {
+            Err(TriggerAborted(name)) => assert_eq!(name, "t"),
+            r => panic!("unexpected {:?}", r)
+        }
+        assert!(values(&mut db, "items").is_empty());
+    }
+
+    #[test]
+    fn update_of_row_deleted_by_before_trigger() {
+        let delete = Action::Delete { table: "items".to_string(), filter: Some("name = old.name".to_string()) };
+        let mut db = setup(trigger(Timing::Before, Event::Update, None, delete));
+        let ident = db.get_table("items").unwrap().add_record(&item("a", 1)).unwrap();
+        match db.get_table("items").unwrap().upd_record(ident, &item("a", 2)) {
+            Err(RecordNotFound) => (),
+            r => panic!("unexpected {:?}", r)
+        }
+        assert!(values(&mut db, "items").is_empty());
+    }
+}
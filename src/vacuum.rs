@@ -0,0 +1,119 @@
+use std::collections::BTreeSet;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::db::*;
+use crate::getset::{EasyGet, GetSet};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct VacuumReport {
+    pub records: usize,
+    pub schemas: usize,
+    pub lists: usize,
+    pub postings: usize,
+    pub migrations: usize,
+    pub changes: usize,
+    pub matviews: usize,
+    pub quarantined: usize,
+    pub counters: usize,
+    pub bytes: usize,
+}
+
+// The table a `%t/..`, `&t/..`, `^t/..`, `*t/..`, `+t`, `~t` or `!t` key
+// belongs to.
+fn owner(key: &str) -> &str {
+    key[1..].split('/').next().unwrap_or("")
+}
+
+impl<KV> DB<KV>
+    where KV: GetSet {
+    fn reclaim(&self, key: &str, report: &mut VacuumReport) {
+        report.bytes += key.len() + self.tree.get_unsafe(key).len();
+        self.tree.del(key);
+    }
+
+    // Reclaims the keys under `sigil` of tables that no longer exist.
+    fn sweep(&self, sigil: &str, tables: &BTreeSet<String>, report: &mut VacuumReport) -> usize {
+        let mut n = 0;
+        for k in self.tree.keys(sigil) {
+            if k.len() > sigil.len() && !tables.contains(owner(&k)) {
+                self.reclaim(&k, report);
+                n += 1;
+            }
+        }
+        n
+    }
+
+    pub fn vacuum(&mut self) -> DBResult<VacuumReport> {
+        let tables: BTreeSet<String> = self.get_tables()?.into_iter().collect();
+        let mut live = BTreeSet::new();
+        for t in &tables {
+            let records: Vec<u64> = self.tree.get_value(&format!("/{}", t)).unwrap_or_default();
+            live.extend(records.into_iter().map(|ident| record_key(t, ident)));
+        }
+        let mut report = VacuumReport::default();
+        for k in self.tree.keys("$") {
+            if !live.contains(&k) {
+                self.reclaim(&k, &mut report);
+                report.records += 1;
+            }
+        }
+        for k in self.tree.keys("#") {
+            if !tables.contains(&k[1..]) {
+                self.reclaim(&k, &mut report);
+                report.schemas += 1;
+            }
+        }
+        for k in self.tree.keys("/") {
+            if k != "/" && !tables.contains(&k[1..]) {
+                self.reclaim(&k, &mut report);
+                report.lists += 1;
+            }
+        }
+        let n = self.sweep("%", &tables, &mut report);
+        report.postings = n;
+        let n = self.sweep("&", &tables, &mut report);
+        report.migrations = n;
+        let n = self.sweep("^", &tables, &mut report);
+        report.changes = n;
+        let n = self.sweep("*", &tables, &mut report);
+        report.quarantined = n;
+        let n = self.sweep("+", &tables, &mut report);
+        report.counters = n;
+        let n = self.sweep("~", &tables, &mut report) + self.sweep("!", &tables, &mut report);
+        report.matviews = n;
+        let views: Vec<String> = self.tree.get_value("~").unwrap_or_default();
+        if views.iter().any(|v| !tables.contains(v)) {
+            let views: Vec<String> = views.into_iter().filter(|v| tables.contains(v)).collect();
+            self.tree.set_value("~", &views);
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::getset::MemStore;
+
+    #[test]
+    fn reclaims_keys_of_dropped_tables() {
+        let mut db = DB::in_memory(MemStore::default());
+        let schema = Schema { columns: vec![Column::new("a", Type::Integer)], ..Schema::default() };
+        db.add_table("kept", &schema).unwrap();
+        db.get_table("kept").unwrap().add_record(&[DBValue::Integer(1)]).unwrap();
+        let before = db.tree.keys("");
+        for k in &["$gone/1", "#gone", "/gone", "%gone/a/x", "&gone/00000000000000000001", "^gone/00000000000000000009", "*gone/1", "+gone", "~gone", "!gone"] {
+            db.tree.set_value(k, &0u8);
+        }
+        db.tree.set_value("~", &vec!["gone".to_string()]);
+
+        let report = db.vacuum().unwrap();
+        assert_eq!((report.records, report.schemas, report.lists), (1, 1, 1));
+        assert_eq!((report.postings, report.migrations, report.changes), (1, 1, 1));
+        assert_eq!((report.quarantined, report.counters, report.matviews), (1, 1, 2));
+        let mut after = db.tree.keys("");
+        after.retain(|k| k != "~");
+        assert_eq!(after, before);
+    }
+}